@@ -5,7 +5,9 @@ use proc_macro::TokenStream;
 use proc_macro2::{Ident, Literal};
 use quote::quote;
 use syn::parse::{Parse, ParseStream};
-use syn::{parse_macro_input, parse_quote, ItemFn, Token};
+use syn::{
+    parse_macro_input, parse_quote, GenericArgument, ItemFn, PathArguments, ReturnType, Token, Type,
+};
 
 mod kw {
     syn::custom_keyword!(cached);
@@ -55,6 +57,37 @@ impl Parse for DocExtractor {
     }
 }
 
+/// Extract `T` from a return type of form `Result<T>`.
+fn result_ok_type(output: &ReturnType) -> Option<&Type> {
+    let ReturnType::Type(_, ty) = output else {
+        return None;
+    };
+    let Type::Path(path) = &**ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Result" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    match args.args.first()? {
+        GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    }
+}
+
+/// Whether the type is `&str`.
+fn is_str_ref(ty: &Type) -> bool {
+    match ty {
+        Type::Reference(reference) => {
+            matches!(&*reference.elem, Type::Path(path) if path.path.is_ident("str"))
+        }
+        _ => false,
+    }
+}
+
 #[allow(clippy::missing_panics_doc, clippy::too_many_lines)]
 #[proc_macro_attribute]
 pub fn helper_func(args: TokenStream, input: TokenStream) -> TokenStream {
@@ -75,6 +108,15 @@ pub fn helper_func(args: TokenStream, input: TokenStream) -> TokenStream {
             },
         })
         .collect();
+    let arg_types: Vec<_> = input
+        .sig
+        .inputs
+        .iter()
+        .filter_map(|arg| match arg {
+            syn::FnArg::Receiver(_) => None,
+            syn::FnArg::Typed(pat_type) => Some(&*pat_type.ty),
+        })
+        .collect();
     let block = &input.block;
 
     let old_func_ident = Ident::new(&format!("__{}", input.sig.ident), input.sig.ident.span());
@@ -89,6 +131,15 @@ pub fn helper_func(args: TokenStream, input: TokenStream) -> TokenStream {
         }
     };
 
+    let ret_ty = match result_ok_type(&input.sig.output) {
+        Some(ty) => ty,
+        None => {
+            return syn::Error::new_spanned(&input.sig.output, "helper must return `Result<T>`")
+                .to_compile_error()
+                .into();
+        }
+    };
+
     let registry = {
         let util_ident = Ident::new(
             &format!("__UTIL_{}", input.sig.ident),
@@ -111,6 +162,7 @@ pub fn helper_func(args: TokenStream, input: TokenStream) -> TokenStream {
         }
         .to_string();
         let func_ident = &input.sig.ident;
+        let inputs = &input.sig.inputs;
         quote! {
             #[allow(non_upper_case_globals)]
             #[linkme::distributed_slice(crate::UTILS)]
@@ -118,7 +170,13 @@ pub fn helper_func(args: TokenStream, input: TokenStream) -> TokenStream {
                 #func_sig,
                 #func_doc,
                 once_cell::sync::Lazy::new(|| {
-                    minijinja::value::Value::from_function(#func_ident)
+                    // Results are converted via serde so that helpers may return structured values.
+                    #[allow(clippy::used_underscore_binding)]
+                    fn wrapper(#inputs) -> Result<minijinja::value::Value, minijinja::Error> {
+                        #func_ident(#(#arg_names),*)
+                            .map(|value| minijinja::value::Value::from_serializable(&value))
+                    }
+                    minijinja::value::Value::from_function(wrapper)
                 })
             );
         }
@@ -129,7 +187,7 @@ pub fn helper_func(args: TokenStream, input: TokenStream) -> TokenStream {
     };
     let derived_func = {
         let mut sig = input.sig.clone();
-        sig.output = parse_quote!( -> Result<String, minijinja::Error> );
+        sig.output = parse_quote!( -> Result<#ret_ty, minijinja::Error> );
         match args.cached {
             None => quote! {
                 #allow_attrs
@@ -145,24 +203,42 @@ pub fn helper_func(args: TokenStream, input: TokenStream) -> TokenStream {
                     CacheName::Explicit(ident) => ident.to_string(),
                     CacheName::Implicit => sig.ident.to_string(),
                 };
+                let cached_ident = Ident::new(
+                    &format!("__CACHED_{}", input.sig.ident),
+                    input.sig.ident.span(),
+                );
+                let arity = arg_names.len();
+                // Arguments are recovered from their string representation in the cache path.
+                let live_args = arg_types.iter().enumerate().map(|(i, ty)| {
+                    if is_str_ref(ty) {
+                        quote! { &args[#i] }
+                    } else {
+                        quote! {
+                            args[#i].parse().map_err(|e| {
+                                eyre::eyre!("Invalid argument {:?}: {}", args[#i], e)
+                            })?
+                        }
+                    }
+                });
                 quote! {
                     #allow_attrs
                     #vis #sig {
-                        let store = crate::store::get_global_store();
                         let path = &[#cache_key.to_string(), #((&#arg_names).to_string()),*];
-                        if let Some(cache) = store.try_get_cached(path) {
-                            Ok(cache)
-                        } else {
-                            let value = #old_func_ident(#(#arg_names),*);
-                            let value = value.map_err(|e| {
-                                minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, e.to_string())
-                            });
-                            if let Ok(ref value) = value {
-                                store.put_cache(path, value.to_string());
-                            }
-                            value
-                        }
+                        crate::store::cached(path, || #old_func_ident(#(#arg_names),*))
                     }
+
+                    #[allow(non_upper_case_globals)]
+                    #[linkme::distributed_slice(crate::CACHED_UTILS)]
+                    static #cached_ident: (
+                        &str,
+                        usize,
+                        fn(&[String]) -> crate::Result<serde_json::Value>,
+                    ) = (#cache_key, #arity, {
+                        fn live(args: &[String]) -> crate::Result<serde_json::Value> {
+                            Ok(serde_json::to_value(#old_func_ident(#(#live_args),*)?)?)
+                        }
+                        live
+                    });
                 }
             }
         }
@@ -0,0 +1,122 @@
+//! Archive unpacking with `fetchzip` semantics.
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use eyre::{bail, eyre, WrapErr};
+use flate2::read::GzDecoder;
+use xz2::read::XzDecoder;
+use zip::ZipArchive;
+
+use crate::Result;
+
+const MAGIC_GZIP: &[u8] = &[0x1f, 0x8b];
+const MAGIC_XZ: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const MAGIC_ZIP: &[u8] = b"PK\x03\x04";
+
+const S_IFMT: u32 = 0o170_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// Unpack `archive` into `dest` like `fetchzip` does, and returns the path of the unpacked tree.
+///
+/// The archive format (tar.gz, tar.xz or zip) is detected by its magic bytes.
+/// The archive must contain exactly one top-level entry, which is stripped.
+/// If the entry is a single file, it's placed in a directory instead.
+pub fn unpack_stripped(archive: &mut File, dest: &Path) -> Result<PathBuf> {
+    let unpack_dir = dest.join("unpack");
+    fs::create_dir(&unpack_dir)?;
+    unpack(archive, &unpack_dir)?;
+    normalize_permissions(&unpack_dir)?;
+
+    let mut entries = fs::read_dir(&unpack_dir)?.collect::<Result<Vec<_>, _>>()?;
+    if entries.len() != 1 {
+        bail!("Archive must contain a single file or directory");
+    }
+    let root = entries.pop().unwrap();
+    if root.file_type()?.is_dir() {
+        Ok(root.path())
+    } else {
+        let out = dest.join("out");
+        fs::create_dir(&out)?;
+        fs::rename(root.path(), out.join(root.file_name()))?;
+        Ok(out)
+    }
+}
+
+fn unpack(archive: &mut File, dest: &Path) -> Result<()> {
+    let mut magic = [0; 6];
+    let len = archive.read(&mut magic)?;
+    archive.seek(SeekFrom::Start(0))?;
+    let magic = &magic[..len];
+
+    if magic.starts_with(MAGIC_GZIP) {
+        unpack_tar(GzDecoder::new(BufReader::new(archive)), dest)
+    } else if magic.starts_with(MAGIC_XZ) {
+        unpack_tar(XzDecoder::new(BufReader::new(archive)), dest)
+    } else if magic.starts_with(MAGIC_ZIP) {
+        unpack_zip(archive, dest)
+    } else {
+        Err(eyre!("Unsupported archive format"))
+    }
+}
+
+fn unpack_tar(reader: impl Read, dest: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(false);
+    archive
+        .unpack(dest)
+        .wrap_err("Failed to unpack tar archive")?;
+    Ok(())
+}
+
+fn unpack_zip(reader: impl Read + Seek, dest: &Path) -> Result<()> {
+    let mut archive = ZipArchive::new(reader)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let path = dest.join(
+            entry
+                .enclosed_name()
+                .ok_or_else(|| eyre!("Invalid path in zip archive: {}", entry.name()))?,
+        );
+        if entry.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mode = entry.unix_mode();
+        if mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK) {
+            let mut target = String::new();
+            entry.read_to_string(&mut target)?;
+            symlink(target, &path)?;
+        } else {
+            std::io::copy(&mut entry, &mut File::create(&path)?)?;
+            if let Some(mode) = mode {
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o777))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Make sure everything is readable and writable by us, so that we can hash and remove it.
+fn normalize_permissions(path: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        return Ok(());
+    }
+    let mode = metadata.permissions().mode();
+    if metadata.is_dir() {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o700))?;
+        for entry in fs::read_dir(path)? {
+            normalize_permissions(&entry?.path())?;
+        }
+    } else {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o600))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,262 @@
+//! Project configuration, read from `nix-template.toml` at the project root.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use eyre::WrapErr;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+
+use crate::{format, Result};
+
+pub const CONFIG_FILE: &str = "nix-template.toml";
+
+const DEFAULT_INCLUDE: &[&str] = &["*.tmpl.*"];
+const DEFAULT_SUFFIX: &str = ".nix";
+pub const DEFAULT_HEADER: &str = "GENERATED BY nix-template. DO NOT EDIT.";
+
+/// Rules deciding which files are templates and how they are generated.
+///
+/// Unset fields fall back to the enclosing rules.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Rules {
+    /// Globs of templates. Globs without `/` match file names, and others match paths relative to
+    /// the project root.
+    include: Option<Vec<String>>,
+    /// Globs of files which are never templates, even if included.
+    exclude: Option<Vec<String>>,
+    /// Suffix of template file names, replaced by `suffix` in generated file names.
+    /// If unset, `NAME.tmpl.EXT` generates `NAME.EXT`.
+    template_suffix: Option<String>,
+    /// Defaults to `.nix`.
+    suffix: Option<String>,
+    /// Text of the comment on top of generated files. An empty string disables it.
+    header: Option<String>,
+}
+
+impl Rules {
+    fn merge(&mut self, other: &Self) {
+        fn set<T: Clone>(this: &mut Option<T>, other: &Option<T>) {
+            if other.is_some() {
+                this.clone_from(other);
+            }
+        }
+        set(&mut self.include, &other.include);
+        set(&mut self.exclude, &other.exclude);
+        set(&mut self.template_suffix, &other.template_suffix);
+        set(&mut self.suffix, &other.suffix);
+        set(&mut self.header, &other.header);
+    }
+}
+
+/// Rules applying to a directory and its descendants only.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Override {
+    /// The directory, relative to the project root.
+    path: PathBuf,
+    #[serde(flatten)]
+    rules: Rules,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    /// Directory containing the config file. Relative paths in the config are resolved against it.
+    #[serde(skip)]
+    pub root: PathBuf,
+    #[serde(flatten)]
+    rules: Rules,
+    /// The lock file to use.
+    lock: Option<PathBuf>,
+    /// Directory to write generated files to, mirroring the template directory.
+    out_dir: Option<PathBuf>,
+    /// Instantiate without fetching values missing from the lock file by default.
+    #[serde(default)]
+    pub frozen: bool,
+    /// Number of templates to render in parallel by default.
+    pub jobs: Option<usize>,
+    #[serde(default, rename = "override")]
+    overrides: Vec<Override>,
+}
+
+/// Rules resolved for a single file.
+pub struct Resolved {
+    include: Globs,
+    exclude: Globs,
+    template_suffix: Option<String>,
+    suffix: String,
+    pub header: String,
+}
+
+impl Resolved {
+    /// Whether `path`, relative to the project root, is a template.
+    pub fn is_template(&self, path: &Path) -> bool {
+        self.include.is_match(path) && !self.exclude.is_match(path)
+    }
+    /// File name of the file generated from template `name`.
+    pub fn target_name(&self, name: &str) -> String {
+        match &self.template_suffix {
+            Some(template_suffix) => {
+                let stem = name.strip_suffix(template_suffix).unwrap_or(name);
+                format!("{stem}{}", self.suffix)
+            }
+            None => format::target_name(name),
+        }
+    }
+}
+
+/// Globs matching either file names or relative paths, like those in `.gitignore`.
+struct Globs {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Globs {
+    fn new(patterns: &[String]) -> Result<Self> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for pattern in patterns {
+            // `*` never crosses `/`, so that e.g. `*.tmpl.*` doesn't match `x.tmpl.d/foo`.
+            let glob = GlobBuilder::new(pattern).literal_separator(true).build()?;
+            if pattern.contains('/') {
+                paths.add(glob);
+            } else {
+                names.add(glob);
+            }
+        }
+        Ok(Self {
+            names: names.build()?,
+            paths: paths.build()?,
+        })
+    }
+
+    fn is_match(&self, path: &Path) -> bool {
+        path.file_name()
+            .is_some_and(|name| self.names.is_match(name))
+            || self.paths.is_match(path)
+    }
+}
+
+impl Config {
+    /// Find the config file in `path` or its ancestors, and load it.
+    ///
+    /// If there's none, the default config rooted at `path` is returned.
+    pub fn discover(path: &Path) -> Result<Self> {
+        let path = fs::canonicalize(path)
+            .wrap_err_with(|| format!("Directory {} not found", path.display()))?;
+        for dir in path.ancestors() {
+            let file = dir.join(CONFIG_FILE);
+            if file.is_file() {
+                return Self::load(&file);
+            }
+        }
+        Ok(Self {
+            root: path,
+            ..Self::default()
+        })
+    }
+
+    fn load(file: &Path) -> Result<Self> {
+        let content = fs::read_to_string(file)?;
+        let mut config: Self = toml::from_str(&content)
+            .wrap_err_with(|| format!("Failed to parse {}", file.display()))?;
+        config.root = file.parent().unwrap().to_path_buf();
+        Ok(config)
+    }
+
+    /// The lock file configured, resolved against the project root.
+    pub fn lock(&self) -> Option<PathBuf> {
+        self.lock.as_ref().map(|lock| self.root.join(lock))
+    }
+
+    /// The output directory configured, resolved against the project root.
+    pub fn out_dir(&self) -> Option<PathBuf> {
+        self.out_dir.as_ref().map(|dir| self.root.join(dir))
+    }
+
+    /// Resolve rules for `path`, relative to the project root.
+    ///
+    /// Overrides are applied in order, so that later ones take precedence.
+    pub fn rules_for(&self, path: &Path) -> Result<Resolved> {
+        let mut rules = self.rules.clone();
+        for item in &self.overrides {
+            if path.starts_with(&item.path) {
+                rules.merge(&item.rules);
+            }
+        }
+        Ok(Resolved {
+            include: Globs::new(
+                &rules
+                    .include
+                    .unwrap_or_else(|| DEFAULT_INCLUDE.iter().map(ToString::to_string).collect()),
+            )?,
+            exclude: Globs::new(rules.exclude.as_deref().unwrap_or_default())?,
+            template_suffix: rules.template_suffix,
+            suffix: rules.suffix.unwrap_or_else(|| DEFAULT_SUFFIX.to_string()),
+            header: rules.header.unwrap_or_else(|| DEFAULT_HEADER.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::config::{Config, CONFIG_FILE, DEFAULT_HEADER};
+
+    #[test]
+    fn must_resolve_overrides() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(
+            root.path().join(CONFIG_FILE),
+            r#"
+                exclude = ["vendor/**"]
+                lock = "nix/template.lock"
+                out-dir = "generated"
+                jobs = 2
+
+                [[override]]
+                path = "hosts"
+                include = ["*.tmpl.nix", "*.nix.in"]
+                template-suffix = ".nix.in"
+                header = "Managed by nix-template."
+
+                [[override]]
+                path = "hosts/legacy"
+                header = ""
+            "#,
+        )
+        .unwrap();
+        fs::create_dir_all(root.path().join("hosts/legacy")).unwrap();
+
+        let config = Config::discover(&root.path().join("hosts/legacy")).unwrap();
+        assert_eq!(config.root, fs::canonicalize(root.path()).unwrap());
+        assert_eq!(config.lock(), Some(config.root.join("nix/template.lock")));
+        assert_eq!(config.out_dir(), Some(config.root.join("generated")));
+        assert_eq!(config.jobs, Some(2));
+        assert!(!config.frozen);
+
+        let rules = config.rules_for(Path::new("a.tmpl.nix")).unwrap();
+        assert!(rules.is_template(Path::new("a.tmpl.nix")));
+        assert!(!rules.is_template(Path::new("vendor/a.tmpl.nix")));
+        assert!(!rules.is_template(Path::new("a.nix.in")));
+        assert!(rules.is_template(Path::new("a.tmpl.json")));
+        assert!(rules.is_template(Path::new("hosts/a.tmpl.json")));
+        assert!(!rules.is_template(Path::new("x.tmpl.d/foo")));
+        assert!(!rules.is_template(Path::new("install.tmpl")));
+        assert_eq!(rules.target_name("a.tmpl.nix"), "a.nix");
+        assert_eq!(rules.target_name("a.tmpl.json"), "a.json");
+        assert_eq!(rules.header, DEFAULT_HEADER);
+
+        let rules = config.rules_for(Path::new("hosts/a.nix.in")).unwrap();
+        assert!(rules.is_template(Path::new("hosts/a.nix.in")));
+        assert_eq!(rules.target_name("a.nix.in"), "a.nix");
+        assert_eq!(rules.header, "Managed by nix-template.");
+
+        let rules = config
+            .rules_for(Path::new("hosts/legacy/a.nix.in"))
+            .unwrap();
+        assert_eq!(rules.header, "");
+    }
+}
@@ -0,0 +1,142 @@
+//! Machine-readable progress events, printed as newline-delimited JSON.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use eyre::Report;
+use serde::Serialize;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Print events from now on.
+///
+/// Events are printed to stdout, unless `to_stderr` is set because stdout is taken by other output.
+pub fn enable(to_stderr: bool) {
+    ENABLED.store(true, Ordering::SeqCst);
+    TO_STDERR.store(to_stderr, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cache {
+    Hit,
+    Miss,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    TemplateStarted {
+        template: &'a str,
+    },
+    TemplateFinished {
+        template: &'a str,
+        target: &'a str,
+        /// `created`, `updated` or `unchanged` when instantiating, and `fresh` or `stale` when checking.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        outcome: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        diff: Option<&'a str>,
+    },
+    /// A generated file was removed because its template is gone.
+    ///
+    /// `template` is null if the file was recognized by its header instead of the manifest.
+    OutputRemoved {
+        target: &'a str,
+        template: Option<&'a str>,
+    },
+    /// A generated file whose template is gone was found.
+    ///
+    /// `template` is null if the file was recognized by its header instead of the manifest.
+    Orphaned {
+        target: &'a str,
+        template: Option<&'a str>,
+    },
+    HelperCall {
+        key: &'a str,
+        cache: Cache,
+        template: Option<&'a str>,
+    },
+    Fetch {
+        key: &'a str,
+        duration_ms: u128,
+    },
+    /// `old` is null for added entries, and `new` is null for removed ones.
+    LockEntryChanged {
+        key: &'a str,
+        old: Option<&'a serde_json::Value>,
+        new: Option<&'a serde_json::Value>,
+    },
+    Outdated {
+        key: &'a str,
+        locked: &'a serde_json::Value,
+        upstream: &'a serde_json::Value,
+        templates: Vec<&'a str>,
+    },
+    Error {
+        message: String,
+        file: Option<&'a str>,
+        line: Option<usize>,
+    },
+}
+
+/// Print `event` if events are enabled.
+pub fn emit(event: &Event) {
+    if enabled() {
+        let line = serde_json::to_string(event).unwrap();
+        if TO_STDERR.load(Ordering::SeqCst) {
+            eprintln!("{line}");
+        } else {
+            println!("{line}");
+        }
+    }
+}
+
+/// Print an error event for `report`, locating it in the failing template if possible.
+pub fn emit_error(report: &Report) {
+    let location = report
+        .chain()
+        .find_map(|e| e.downcast_ref::<minijinja::Error>());
+    emit(&Event::Error {
+        message: format!("{report:#}"),
+        file: location.and_then(minijinja::Error::name),
+        line: location.and_then(minijinja::Error::line),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::events::{Cache, Event};
+
+    #[test]
+    fn must_serialize_events() {
+        let event = Event::HelperCall {
+            key: "commit_of_git/https://example.com/a.git/main",
+            cache: Cache::Hit,
+            template: Some("a.tmpl.nix"),
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({
+                "event": "helper_call",
+                "key": "commit_of_git/https://example.com/a.git/main",
+                "cache": "hit",
+                "template": "a.tmpl.nix",
+            })
+        );
+        let event = Event::LockEntryChanged {
+            key: "g",
+            old: None,
+            new: Some(&json!("g")),
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({ "event": "lock_entry_changed", "key": "g", "old": null, "new": "g" })
+        );
+    }
+}
@@ -0,0 +1,29 @@
+//! HTTP downloads.
+use std::io::Read;
+
+use eyre::WrapErr;
+use once_cell::sync::Lazy;
+
+use crate::Result;
+
+static AGENT: Lazy<ureq::Agent> = Lazy::new(|| {
+    ureq::AgentBuilder::new()
+        .user_agent(concat!("nix-template/", env!("CARGO_PKG_VERSION")))
+        .build()
+});
+
+/// Send a GET request to `url` and return a reader of the response body.
+pub fn get(url: &str) -> Result<impl Read + Send> {
+    get_with_headers(url, &[])
+}
+
+/// Send a GET request to `url` with extra headers and return a reader of the response body.
+pub fn get_with_headers(url: &str, headers: &[(&str, &str)]) -> Result<impl Read + Send> {
+    let req = headers
+        .iter()
+        .fold(AGENT.get(url), |req, (name, value)| req.set(name, value));
+    let resp = req
+        .call()
+        .wrap_err_with(|| format!("Failed to download {url}"))?;
+    Ok(resp.into_reader())
+}
@@ -0,0 +1,170 @@
+//! Counterparts of the GitHub helpers for other forges.
+//!
+//! Repository and archive urls follow the conventions of the corresponding `fetchFrom*` fetchers in
+//! nixpkgs, so hashes are those of the unpacked archives they download.
+use nix_template_macros::helper_func;
+
+use crate::utils::{commit_of_git, hash_from_tarball};
+use crate::Result;
+
+fn gitlab_url(owner: &str, repo: &str, domain: Option<&str>) -> String {
+    // `owner` may contain nested groups, e.g. `group/subgroup`.
+    let domain = domain.unwrap_or("gitlab.com");
+    format!("https://{domain}/{owner}/{repo}.git")
+}
+
+fn gitlab_archive_url(owner: &str, repo: &str, rev: &str, domain: Option<&str>) -> String {
+    // Archives are served by the API, which takes the escaped project path.
+    let domain = domain.unwrap_or("gitlab.com");
+    let slug = format!("{owner}/{repo}")
+        .replace('.', "%2E")
+        .replace('/', "%2F");
+    let rev = rev
+        .replace('%', "%25")
+        .replace('+', "%2B")
+        .replace('/', "%2F");
+    format!("https://{domain}/api/v4/projects/{slug}/repository/archive.tar.gz?sha={rev}")
+}
+
+fn gitea_url(domain: &str, owner: &str, repo: &str) -> String {
+    format!("https://{domain}/{owner}/{repo}.git")
+}
+
+fn gitea_archive_url(domain: &str, owner: &str, repo: &str, rev: &str) -> String {
+    format!("https://{domain}/{owner}/{repo}/archive/{rev}.tar.gz")
+}
+
+fn sourcehut_url(owner: &str, repo: &str, domain: Option<&str>) -> String {
+    let domain = domain.unwrap_or("sr.ht");
+    let owner = owner.trim_start_matches('~');
+    format!("https://git.{domain}/~{owner}/{repo}")
+}
+
+fn sourcehut_archive_url(owner: &str, repo: &str, rev: &str, domain: Option<&str>) -> String {
+    format!(
+        "{}/archive/{rev}.tar.gz",
+        sourcehut_url(owner, repo, domain)
+    )
+}
+
+fn bitbucket_url(owner: &str, repo: &str) -> String {
+    format!("https://bitbucket.org/{owner}/{repo}.git")
+}
+
+fn bitbucket_archive_url(owner: &str, repo: &str, rev: &str) -> String {
+    format!("https://bitbucket.org/{owner}/{repo}/get/{rev}.tar.gz")
+}
+
+/// Returns the commit hash of given GitLab repo and rev. `domain` defaults to gitlab.com.
+#[helper_func]
+fn commit_of_gitlab(owner: &str, repo: &str, rev: &str, domain: Option<&str>) -> Result<String> {
+    Ok(commit_of_git(&gitlab_url(owner, repo, domain), rev)?)
+}
+
+/// Returns the sha256 hash (SRI format) of given GitLab repo and rev, as expected by
+/// `fetchFromGitLab`. `domain` defaults to gitlab.com.
+#[helper_func]
+fn hash_from_gitlab(owner: &str, repo: &str, rev: &str, domain: Option<&str>) -> Result<String> {
+    Ok(hash_from_tarball(&gitlab_archive_url(
+        owner, repo, rev, domain,
+    ))?)
+}
+
+/// Returns the commit hash of given Gitea/Forgejo repo and rev.
+#[helper_func]
+fn commit_of_gitea(domain: &str, owner: &str, repo: &str, rev: &str) -> Result<String> {
+    Ok(commit_of_git(&gitea_url(domain, owner, repo), rev)?)
+}
+
+/// Returns the sha256 hash (SRI format) of given Gitea/Forgejo repo and rev, as expected by
+/// `fetchFromGitea`.
+#[helper_func]
+fn hash_from_gitea(domain: &str, owner: &str, repo: &str, rev: &str) -> Result<String> {
+    Ok(hash_from_tarball(&gitea_archive_url(
+        domain, owner, repo, rev,
+    ))?)
+}
+
+/// Returns the commit hash of given SourceHut repo and rev. `domain` defaults to sr.ht.
+#[helper_func]
+fn commit_of_sourcehut(owner: &str, repo: &str, rev: &str, domain: Option<&str>) -> Result<String> {
+    Ok(commit_of_git(&sourcehut_url(owner, repo, domain), rev)?)
+}
+
+/// Returns the sha256 hash (SRI format) of given SourceHut repo and rev, as expected by
+/// `fetchFromSourcehut`. `domain` defaults to sr.ht.
+#[helper_func]
+fn hash_from_sourcehut(owner: &str, repo: &str, rev: &str, domain: Option<&str>) -> Result<String> {
+    Ok(hash_from_tarball(&sourcehut_archive_url(
+        owner, repo, rev, domain,
+    ))?)
+}
+
+/// Returns the commit hash of given Bitbucket repo and rev.
+#[helper_func]
+fn commit_of_bitbucket(owner: &str, repo: &str, rev: &str) -> Result<String> {
+    Ok(commit_of_git(&bitbucket_url(owner, repo), rev)?)
+}
+
+/// Returns the sha256 hash (SRI format) of given Bitbucket repo and rev, as expected by
+/// `fetchFromBitbucket`.
+#[helper_func]
+fn hash_from_bitbucket(owner: &str, repo: &str, rev: &str) -> Result<String> {
+    Ok(hash_from_tarball(&bitbucket_archive_url(owner, repo, rev))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::forges::{
+        bitbucket_archive_url, bitbucket_url, gitea_archive_url, gitea_url, gitlab_archive_url,
+        gitlab_url, sourcehut_archive_url, sourcehut_url,
+    };
+
+    #[test]
+    fn must_follow_nixpkgs_conventions() {
+        assert_eq!(
+            gitlab_url("group/subgroup", "repo", None),
+            "https://gitlab.com/group/subgroup/repo.git"
+        );
+        assert_eq!(
+            gitlab_url("gnome", "gtk", Some("gitlab.gnome.org")),
+            "https://gitlab.gnome.org/gnome/gtk.git"
+        );
+        assert_eq!(
+            gitea_url("codeberg.org", "forgejo", "forgejo"),
+            "https://codeberg.org/forgejo/forgejo.git"
+        );
+        assert_eq!(
+            sourcehut_url("~sircmpwn", "scdoc", None),
+            "https://git.sr.ht/~sircmpwn/scdoc"
+        );
+        assert_eq!(
+            sourcehut_url("sircmpwn", "scdoc", Some("example.org")),
+            "https://git.example.org/~sircmpwn/scdoc"
+        );
+        assert_eq!(
+            bitbucket_url("owner", "repo"),
+            "https://bitbucket.org/owner/repo.git"
+        );
+    }
+
+    #[test]
+    fn must_follow_nixpkgs_archive_conventions() {
+        assert_eq!(
+            gitlab_archive_url("group/subgroup", "repo.rs", "v1.0+1", None),
+            "https://gitlab.com/api/v4/projects/group%2Fsubgroup%2Frepo%2Ers/repository/archive.tar.gz?sha=v1.0%2B1"
+        );
+        assert_eq!(
+            gitea_archive_url("codeberg.org", "forgejo", "forgejo", "v1.0"),
+            "https://codeberg.org/forgejo/forgejo/archive/v1.0.tar.gz"
+        );
+        assert_eq!(
+            sourcehut_archive_url("~sircmpwn", "scdoc", "1.11.2", None),
+            "https://git.sr.ht/~sircmpwn/scdoc/archive/1.11.2.tar.gz"
+        );
+        assert_eq!(
+            bitbucket_archive_url("owner", "repo", "abc"),
+            "https://bitbucket.org/owner/repo/get/abc.tar.gz"
+        );
+    }
+}
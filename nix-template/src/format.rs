@@ -0,0 +1,104 @@
+//! Per-format behaviors of generated files, decided by their extensions.
+use std::path::Path;
+
+use minijinja::AutoEscape;
+
+use crate::nix;
+
+/// Extension of the file generated from template `name`, e.g. `json` for `a.tmpl.json`.
+pub fn target_extension(name: &str) -> Option<&str> {
+    let name = name.strip_suffix(".tmpl").unwrap_or(name);
+    let name = Path::new(name).file_name()?.to_str()?;
+    name.rsplit_once('.').map(|(_, ext)| ext)
+}
+
+/// Generated file name of template `name`, which is `NAME.EXT` for `NAME.tmpl.EXT`.
+pub fn target_name(name: &str) -> String {
+    if let Some(stem) = name.strip_suffix(".tmpl") {
+        return stem.to_string();
+    }
+    match name.rsplit_once(".tmpl.") {
+        Some((stem, ext)) => format!("{stem}.{ext}"),
+        None => name.to_string(),
+    }
+}
+
+/// Format `text` as a line comment in files of extension `ext`.
+///
+/// Returns `None` if the format doesn't support comments.
+pub fn comment(ext: Option<&str>, text: &str) -> Option<String> {
+    match ext {
+        Some("json") => None,
+        Some(
+            "js" | "mjs" | "ts" | "jsonc" | "json5" | "rs" | "go" | "c" | "h" | "cc" | "cpp"
+            | "hpp" | "java" | "kt" | "scala" | "swift" | "dart" | "zig",
+        ) => Some(format!("// {text}")),
+        Some("xml" | "html" | "htm" | "md") => Some(format!("<!-- {text} -->")),
+        Some("lua" | "sql" | "hs") => Some(format!("-- {text}")),
+        Some("ini") => Some(format!("; {text}")),
+        Some("vim") => Some(format!("\" {text}")),
+        // Nix, shell scripts, TOML, YAML and most config files.
+        _ => Some(format!("# {text}")),
+    }
+}
+
+/// Prepend `header` to `content` as its own line, after the shebang if any.
+pub fn with_header(header: &str, content: &str) -> String {
+    match content.split_once('\n') {
+        Some((shebang, rest)) if shebang.starts_with("#!") => {
+            format!("{shebang}\n{header}\n{rest}")
+        }
+        _ => format!("{header}\n{content}"),
+    }
+}
+
+/// Whether `content` starts with the line `header`, as prepended by [`with_header`].
+pub fn has_header(content: &str, header: &str) -> bool {
+    let mut lines = content.lines();
+    let first = match lines.next() {
+        Some(shebang) if shebang.starts_with("#!") => lines.next(),
+        line => line,
+    };
+    first == Some(header)
+}
+
+/// Default auto escaping of templates generating files of extension `ext`.
+pub fn auto_escape(ext: Option<&str>) -> AutoEscape {
+    match ext {
+        Some("nix") => nix::AUTO_ESCAPE,
+        Some("html" | "htm" | "xml") => AutoEscape::Html,
+        // Values are serialized to JSON, which is also valid YAML.
+        Some("json" | "json5" | "js" | "yaml" | "yml") => AutoEscape::Json,
+        _ => AutoEscape::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::{comment, has_header, target_extension, target_name, with_header};
+
+    #[test]
+    fn must_map_names_by_extension() {
+        assert_eq!(target_name("a.tmpl.nix"), "a.nix");
+        assert_eq!(target_name("sources.tmpl.json"), "sources.json");
+        assert_eq!(target_name("ci.tmpl.yml"), "ci.yml");
+        assert_eq!(target_name("install.tmpl"), "install");
+        assert_eq!(target_extension("dir/a.tmpl.nix"), Some("nix"));
+        assert_eq!(target_extension("dir/a.b.tmpl"), Some("b"));
+        assert_eq!(target_extension("dir.d/Makefile.tmpl"), None);
+
+        assert_eq!(comment(Some("json"), "x"), None);
+        assert_eq!(comment(Some("ts"), "x").unwrap(), "// x");
+        assert_eq!(comment(Some("toml"), "x").unwrap(), "# x");
+
+        assert_eq!(with_header("# x", "a\nb\n"), "# x\na\nb\n");
+        assert_eq!(
+            with_header("# x", "#!/bin/sh\necho\n"),
+            "#!/bin/sh\n# x\necho\n"
+        );
+        assert!(has_header("#!/bin/sh\n# x\necho\n", "# x"));
+        assert!(has_header("// x\r\n", "// x"));
+        assert!(!has_header("// x y\n", "// x"));
+        assert!(!has_header("a\n# x\n", "# x"));
+    }
+}
@@ -0,0 +1,232 @@
+//! Helpers backed by the GitHub REST API.
+use std::env;
+
+use console::Emoji;
+use eyre::{eyre, WrapErr};
+use globset::Glob;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use nix_template_macros::helper_func;
+
+use crate::fetch;
+use crate::spinner;
+use crate::Result;
+
+const EMOJI_RELEASE: Emoji = Emoji("🚢 ", "");
+
+const DEFAULT_API_URL: &str = "https://api.github.com";
+/// The maximum page size allowed by the API.
+const PER_PAGE: usize = 100;
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    draft: bool,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// A GitHub release, as returned by release helpers.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReleaseInfo {
+    tag: String,
+    assets: Vec<AssetInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AssetInfo {
+    name: String,
+    /// The download url.
+    url: String,
+}
+
+impl From<Release> for ReleaseInfo {
+    fn from(release: Release) -> Self {
+        Self {
+            tag: release.tag_name,
+            assets: release
+                .assets
+                .into_iter()
+                .map(|asset| AssetInfo {
+                    name: asset.name,
+                    url: asset.browser_download_url,
+                })
+                .collect(),
+        }
+    }
+}
+
+struct Client {
+    api_url: String,
+    token: Option<String>,
+}
+
+impl Client {
+    /// Create a client from `GITHUB_API_URL` (for GitHub Enterprise) and `GITHUB_TOKEN`.
+    fn from_env() -> Self {
+        Self {
+            api_url: env::var("GITHUB_API_URL").unwrap_or_else(|_| DEFAULT_API_URL.to_string()),
+            token: env::var("GITHUB_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+        }
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let url = format!("{}{path}", self.api_url.trim_end_matches('/'));
+        let auth = self.token.as_ref().map(|token| format!("Bearer {token}"));
+        let mut headers = vec![("Accept", "application/vnd.github+json")];
+        if let Some(auth) = &auth {
+            headers.push(("Authorization", auth));
+        }
+        let reader = fetch::get_with_headers(&url, &headers)?;
+        serde_json::from_reader(reader).wrap_err_with(|| format!("Invalid response from {url}"))
+    }
+
+    fn latest_release(&self, owner: &str, repo: &str, prerelease: bool) -> Result<Release> {
+        if prerelease {
+            // Releases are sorted by creation time, newest first, but all of a page may be drafts.
+            for page in 1.. {
+                let releases: Vec<Release> = self.get(&format!(
+                    "/repos/{owner}/{repo}/releases?per_page={PER_PAGE}&page={page}"
+                ))?;
+                let last_page = releases.len() < PER_PAGE;
+                if let Some(release) = releases.into_iter().find(|release| !release.draft) {
+                    return Ok(release);
+                }
+                if last_page {
+                    break;
+                }
+            }
+            Err(eyre!("No release found in {owner}/{repo}"))
+        } else {
+            // This endpoint already skips drafts and prereleases.
+            self.get(&format!("/repos/{owner}/{repo}/releases/latest"))
+        }
+    }
+
+    fn release_by_tag(&self, owner: &str, repo: &str, tag: &str) -> Result<Release> {
+        self.get(&format!("/repos/{owner}/{repo}/releases/tags/{tag}"))
+    }
+}
+
+fn find_asset(release: &Release, pattern: &str) -> Result<String> {
+    let matcher = Glob::new(pattern)?.compile_matcher();
+    release
+        .assets
+        .iter()
+        .find(|asset| matcher.is_match(&asset.name))
+        .map(|asset| asset.browser_download_url.clone())
+        .ok_or_else(|| {
+            eyre!(
+                "No asset matching {pattern} in release {}",
+                release.tag_name
+            )
+        })
+}
+
+/// Returns `{tag, assets: [{name, url}]}` of the latest release of given GitHub repo, skipping
+/// prereleases.
+#[helper_func(cached)]
+fn github_latest_release(owner: &str, repo: &str) -> Result<ReleaseInfo> {
+    let _task = spinner::task(format!(
+        "{EMOJI_RELEASE}Fetching latest release of {owner}/{repo}"
+    ));
+    Ok(Client::from_env()
+        .latest_release(owner, repo, false)?
+        .into())
+}
+
+/// Returns `{tag, assets: [{name, url}]}` of the latest release of given GitHub repo, including
+/// prereleases.
+#[helper_func(cached)]
+fn github_latest_prerelease(owner: &str, repo: &str) -> Result<ReleaseInfo> {
+    let _task = spinner::task(format!(
+        "{EMOJI_RELEASE}Fetching latest release of {owner}/{repo}"
+    ));
+    Ok(Client::from_env().latest_release(owner, repo, true)?.into())
+}
+
+/// Returns the download url of the first asset matching a glob pattern in given GitHub release.
+#[helper_func(cached)]
+fn github_release_asset(owner: &str, repo: &str, tag: &str, pattern: &str) -> Result<String> {
+    let _task = spinner::task(format!(
+        "{EMOJI_RELEASE}Fetching assets of {owner}/{repo}@{tag}"
+    ));
+    find_asset(
+        &Client::from_env().release_by_tag(owner, repo, tag)?,
+        pattern,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::github::{find_asset, Client, ReleaseInfo, PER_PAGE};
+    use crate::testing::TestServer;
+
+    const LATEST: &str = r#"{"tag_name": "v1.2.0", "draft": false, "assets": [
+        {"name": "tool-1.2.0-x86_64-linux.tar.gz", "browser_download_url": "https://example.com/linux"},
+        {"name": "tool-1.2.0-aarch64-darwin.tar.gz", "browser_download_url": "https://example.com/darwin"}
+    ]}"#;
+
+    #[test]
+    fn must_fetch_latest_release() {
+        // A full page of drafts, followed by the page with the latest prerelease.
+        let drafts = vec![r#"{"tag_name": "v3.0.0-draft", "draft": true, "assets": []}"#; PER_PAGE];
+        let drafts = format!("[{}]", drafts.join(","));
+        let releases = format!(
+            r#"[
+                {{"tag_name": "v2.0.0-draft", "draft": true, "assets": []}},
+                {{"tag_name": "v2.0.0-rc.1", "draft": false, "assets": []}},
+                {LATEST}
+            ]"#
+        );
+        let server = TestServer::serve(maplit::hashmap! {
+            format!("/repos/owner/tool/releases?per_page={PER_PAGE}&page=1") => drafts.into_bytes(),
+            format!("/repos/owner/tool/releases?per_page={PER_PAGE}&page=2") => releases.into_bytes(),
+            "/repos/owner/other/releases?per_page=100&page=1".to_string() => b"[]".to_vec(),
+            "/repos/owner/tool/releases/latest".to_string() => LATEST.as_bytes().to_vec(),
+            "/repos/owner/tool/releases/tags/v1.2.0".to_string() => LATEST.as_bytes().to_vec(),
+        });
+        let client = Client {
+            api_url: server.url.clone(),
+            token: Some("secret".to_string()),
+        };
+
+        let release = client.latest_release("owner", "tool", false).unwrap();
+        assert_eq!(
+            serde_json::to_value(ReleaseInfo::from(release)).unwrap(),
+            json!({
+                "tag": "v1.2.0",
+                "assets": [
+                    { "name": "tool-1.2.0-x86_64-linux.tar.gz", "url": "https://example.com/linux" },
+                    { "name": "tool-1.2.0-aarch64-darwin.tar.gz", "url": "https://example.com/darwin" },
+                ],
+            })
+        );
+        let release = client.latest_release("owner", "tool", true).unwrap();
+        assert_eq!(release.tag_name, "v2.0.0-rc.1");
+        assert!(client.latest_release("owner", "other", true).is_err());
+
+        let release = client.release_by_tag("owner", "tool", "v1.2.0").unwrap();
+        assert_eq!(
+            find_asset(&release, "*-x86_64-linux.tar.gz").unwrap(),
+            "https://example.com/linux"
+        );
+        assert!(find_asset(&release, "*.zip").is_err());
+        assert!(client.release_by_tag("owner", "tool", "v0.1.0").is_err());
+
+        let requests = server.requests.lock().unwrap();
+        assert!(requests
+            .iter()
+            .all(|head| head.to_lowercase().contains("authorization: bearer secret")));
+    }
+}
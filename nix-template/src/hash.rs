@@ -0,0 +1,52 @@
+//! Encodings of sha256 digests understood by nix.
+
+const BASE32_CHARS: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Encode a sha256 digest in SRI format, e.g. `sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=`.
+pub fn to_sri(digest: &[u8]) -> String {
+    format!("sha256-{}", base64::encode(digest))
+}
+
+/// Encode a digest in nix's own base32 flavor, as printed by `nix-prefetch-url`.
+pub fn to_nix_base32(digest: &[u8]) -> String {
+    let len = (digest.len() * 8 - 1) / 5 + 1;
+    (0..len)
+        .rev()
+        .map(|n| {
+            let b = n * 5;
+            let (i, j) = (b / 8, b % 8);
+            let lo = u16::from(digest[i]) >> j;
+            let hi = digest.get(i + 1).map_or(0, |&c| u16::from(c) << (8 - j));
+            char::from(BASE32_CHARS[usize::from((lo | hi) & 0x1f)])
+        })
+        .collect()
+}
+
+/// Decode a sha256 hash in SRI format.
+pub fn from_sri(sri: &str) -> Option<Vec<u8>> {
+    sri.strip_prefix("sha256-")
+        .and_then(|hash| base64::decode(hash).ok())
+        .filter(|digest| digest.len() == 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use crate::hash::{from_sri, to_nix_base32, to_sri};
+
+    #[test]
+    fn must_encode() {
+        let digest = Sha256::digest(b"");
+        assert_eq!(
+            to_sri(&digest),
+            "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+        assert_eq!(
+            to_nix_base32(&digest),
+            "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+        assert_eq!(from_sri(&to_sri(&digest)).unwrap(), digest.to_vec());
+        assert!(from_sri("sha512-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").is_none());
+    }
+}
@@ -1,42 +1,67 @@
 #![allow(clippy::module_name_repetitions)]
 
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fmt::Write as _;
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::Write as _;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use console::Emoji;
-use eyre::Report;
-use ignore::types::TypesBuilder;
-use ignore::{Walk, WalkBuilder};
-use indicatif::ProgressBar;
+use eyre::{bail, eyre, Report, WrapErr};
+use globset::{Glob, GlobSetBuilder};
+use ignore::WalkBuilder;
 use linkme::distributed_slice;
 use log::info;
 use minijinja::value::Value;
-use minijinja::{context, Environment, Source};
+use minijinja::{Environment, Source};
 use once_cell::sync::Lazy;
+use rayon::prelude::*;
+use similar::TextDiff;
 
-use crate::spinner::{
-    delete_global_progress_bar, new_global_progress_bar, with_global_progress_bar,
-};
+use crate::config::{Config, Resolved};
+use crate::events::Event;
+use crate::manifest::Manifest;
 use crate::store::{delete_global_store, set_global_store, FileStore};
 
+const EMOJI_DONE: Emoji = Emoji("✨ ", "");
+const EMOJI_REMOVE: Emoji = Emoji("🗑️ ", "");
 const EMOJI_ROCKET: Emoji = Emoji("🚀 ", "");
+const EMOJI_SEARCH: Emoji = Emoji("🔍 ", "");
+const EMOJI_WARN: Emoji = Emoji("⚠️ ", "");
 const EMOJI_WRITE: Emoji = Emoji("📝 ", "");
 
 #[macro_use]
 mod utils;
+mod archive;
+mod config;
+mod events;
+mod fetch;
+mod forges;
+mod format;
+mod github;
+mod hash;
+mod manifest;
+mod nar;
+mod nix;
+mod outdated;
+mod output;
 mod spinner;
 mod store;
+#[cfg(test)]
+mod testing;
+mod vars;
 
 // type Result<T, E = Box<dyn Error + Send + Sync>> = std::result::Result<T, E>;
 type Result<T, E = Report> = std::result::Result<T, E>;
 
 const LOCK_FORMAT: usize = 0;
 
+/// Name of the template read from stdin.
+const STDIN_TEMPLATE: &str = "<stdin>";
+
 #[distributed_slice]
 static UTILS: [(
     /* sig */ &'static str,
@@ -44,7 +69,15 @@ static UTILS: [(
     /* func */ Lazy<Value>,
 )] = [..];
 
+#[distributed_slice]
+static CACHED_UTILS: [(
+    /* cache key */ &'static str,
+    /* arity */ usize,
+    /* uncached func */ fn(&[String]) -> Result<serde_json::Value>,
+)] = [..];
+
 fn populate_environment(env: &mut Environment) {
+    nix::populate_environment(env);
     for (sig, _, func) in UTILS {
         let name = sig.split_once('(').unwrap().0;
         info!("Adding helper function: {}", name);
@@ -74,104 +107,877 @@ struct Args {
     #[arg(default_value = ".")]
     path: PathBuf,
     /// The lock file to use.
-    #[arg(short, long, default_value = "template.lock")]
-    lock: PathBuf,
+    /// Defaults to the one in `nix-template.toml`, or `template.lock`.
+    #[arg(short, long)]
+    lock: Option<PathBuf>,
+    /// Write generated files to this directory, mirroring the layout of the template directory.
+    /// Generated files whose templates are gone are removed from it.
+    /// Defaults to the one in `nix-template.toml`, or next to their templates.
+    #[arg(short, long, global = true)]
+    out_dir: Option<PathBuf>,
+    /// Never fetch values missing from the lock file, and fail instead.
+    /// The lock file will not be written.
+    #[arg(
+        long,
+        visible_alias = "locked",
+        global = true,
+        overrides_with = "no_frozen"
+    )]
+    frozen: bool,
+    /// Fetch values missing from the lock file, even if `nix-template.toml` sets `frozen`.
+    #[arg(long, global = true, overrides_with = "frozen")]
+    no_frozen: bool,
+    /// Number of templates to render in parallel.
+    /// Defaults to the one in `nix-template.toml`, or the number of CPUs.
+    #[arg(short, long, global = true)]
+    jobs: Option<usize>,
+    /// Remove lock entries unused by any template after instantiating.
+    #[arg(long, global = true)]
+    prune: bool,
+    /// The format of progress messages.
+    /// `json` prints newline-delimited JSON events to stdout instead.
+    #[arg(long, value_enum, default_value_t, global = true)]
+    message_format: MessageFormat,
+    /// Set a variable in templates, taking precedence over variables files.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = vars::parse_var, global = true)]
+    var: Vec<(String, String)>,
+    /// Read variables in templates from a TOML, JSON or YAML file.
+    /// Variables of a template may also be put in its sidecar file, e.g. `foo.tmpl.vars.toml`.
+    #[arg(long, global = true)]
+    vars_file: Vec<PathBuf>,
+    /// Expose environment variables as `env` in templates.
+    #[arg(long, global = true)]
+    env: bool,
+    /// Overwrite existing files even if they aren't generated by nix-template.
+    #[arg(long, global = true)]
+    force: bool,
 }
 
-#[derive(Subcommand, Copy, Clone, Eq, PartialEq, Default)]
+#[derive(ValueEnum, Copy, Clone, Eq, PartialEq, Default)]
+enum MessageFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+#[derive(Subcommand, Clone, Eq, PartialEq, Default)]
 enum Commands {
     /// Instantiate the template (default).
-    /// Unused cache values in lock file will NOT be removed.
+    /// Unused cache values in lock file will NOT be removed, unless `--prune` is given.
     #[default]
     Instantiate,
     /// Update the lock file.
-    /// Unused cache values in lock file will be removed.
+    /// Unused cache values in lock file will be removed, unless a filter is given.
     /// No file will be generated.
-    Update,
+    Update {
+        /// Only update entries whose path matches this glob, e.g. `commit_of_git/https://github.com/zimfw/*`.
+        /// Other entries are kept verbatim.
+        #[arg(long)]
+        only: Vec<String>,
+        /// Only update entries used by this template.
+        /// Other entries are kept verbatim.
+        #[arg(long)]
+        file: Vec<PathBuf>,
+    },
+    /// Check that generated files are up to date.
+    /// Only values in lock file are used, and a missing value is an error.
+    /// No file will be written.
+    Check,
+    /// Remove unused cache values from the lock file, while keeping all used ones.
+    /// Missing values are an error, since nothing is fetched. No file will be generated.
+    Gc,
+    /// Render a single template to stdout, using values in the lock file.
+    /// Missing values are fetched and saved to the lock file, unless it's frozen.
+    Render {
+        /// The template to render, or `-` to read from stdin.
+        file: PathBuf,
+        /// Extension of the generated file, deciding the escaping of values.
+        /// Defaults to the one implied by the template name, or `nix` when reading from stdin.
+        #[arg(long)]
+        ext: Option<String>,
+    },
+    /// Remove generated files whose templates are gone.
+    /// They are found in the manifest of the lock file, or by the header of generated files.
+    Clean,
+    /// Report locked values which have newer upstream values.
+    /// Neither the lock file nor any file will be written.
+    Outdated {
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// A template to render, and where to write the result.
+struct Template {
+    path: PathBuf,
+    target: PathBuf,
+    /// The comment line on top of the generated file, if any.
+    header: Option<String>,
+}
+
+/// Find all templates in `path` following rules in `config`.
+///
+/// Files are generated next to their templates, or at the same relative path in `out_dir`.
+fn find_templates(path: &Path, config: &Config, out_dir: Option<&Path>) -> Result<Vec<Template>> {
+    // Rules are relative to the project root. Walked files aren't resolved, since they might be
+    // dangling symlinks or point outside of the project.
+    let dir = fs::canonicalize(path)?;
+    let dir = dir.strip_prefix(&config.root).unwrap_or(Path::new(""));
+    let mut templates = vec![];
+    for file in WalkBuilder::new(path).build() {
+        let file = file?;
+        if file.path().is_dir() {
+            continue;
+        }
+        let relative = dir.join(file.path().strip_prefix(path)?);
+        let rules = config.rules_for(&relative)?;
+        let name = file.file_name().to_string_lossy();
+        if !rules.is_template(&relative) || vars::is_sidecar(&name) {
+            continue;
+        }
+        let target = match out_dir {
+            Some(out_dir) => out_dir.join(file.path().strip_prefix(path)?),
+            None => file.path().to_path_buf(),
+        }
+        .with_file_name(rules.target_name(&name));
+        if target == file.path() {
+            bail!(
+                "Template {} would overwrite itself, check `template-suffix` and `suffix` in {}",
+                file.path().display(),
+                config::CONFIG_FILE
+            );
+        }
+        let header = header_line(&target, &rules);
+        templates.push(Template {
+            target,
+            header,
+            path: file.into_path(),
+        });
+    }
+    Ok(templates)
+}
+
+/// The header line of a file generated at `target` following `rules`, if it has one.
+fn header_line(target: &Path, rules: &Resolved) -> Option<String> {
+    if rules.header.is_empty() {
+        return None;
+    }
+    let ext = target.extension().and_then(OsStr::to_str);
+    format::comment(ext, &rules.header)
+}
+
+/// A generated file whose template is gone.
+struct Orphan {
+    target: PathBuf,
+    /// The template it was generated from, if recorded in the manifest.
+    template: Option<PathBuf>,
+}
+
+/// Find generated files in `template_root` and `out_dir` which aren't generated by any of
+/// `templates`.
+///
+/// Generated files are those recorded in `manifest`, or those starting with the exact header line
+/// their template would have produced. Files in `out_dir` are all considered, including ignored
+/// ones.
+fn find_orphans(
+    template_root: &Path,
+    out_dir: Option<&Path>,
+    config: &Config,
+    templates: &[Template],
+    manifest: &Manifest,
+) -> Result<Vec<Orphan>> {
+    let mut roots = vec![template_root];
+    roots.extend(out_dir.filter(|dir| dir.is_dir()));
+    let mut owned = HashSet::new();
+    for template in templates {
+        owned.insert(output::resolve(&template.target)?);
+        owned.insert(fs::canonicalize(&template.path)?);
+    }
+    let mut orphans: Vec<_> = manifest
+        .iter()
+        .filter(|(target, _)| {
+            !owned.contains(*target)
+                && target.is_file()
+                && roots.iter().any(|root| target.starts_with(root))
+        })
+        .map(|(target, template)| Orphan {
+            target: target.to_path_buf(),
+            template: Some(template.to_path_buf()),
+        })
+        .collect();
+
+    let mut seen: HashSet<_> = orphans.iter().map(|orphan| orphan.target.clone()).collect();
+    for root in roots {
+        let walker = WalkBuilder::new(root)
+            .standard_filters(Some(root) != out_dir)
+            .build();
+        for file in walker {
+            let file = file?;
+            if file.path_is_symlink() || !file.path().is_file() {
+                continue;
+            }
+            let path = fs::canonicalize(file.path())?;
+            if owned.contains(&path) || manifest.contains(&path) || seen.contains(&path) {
+                continue;
+            }
+            // Headers follow the rules of where the template would be.
+            let source = match out_dir.and_then(|dir| path.strip_prefix(dir).ok()) {
+                Some(relative) => template_root.join(relative),
+                None => path.clone(),
+            };
+            let relative = source.strip_prefix(&config.root).unwrap_or(Path::new(""));
+            let header = header_line(&path, &config.rules_for(relative)?);
+            if header.is_some_and(|header| output::is_generated(&path, &header)) {
+                seen.insert(path.clone());
+                orphans.push(Orphan {
+                    target: path,
+                    template: None,
+                });
+            }
+        }
+    }
+    Ok(orphans)
+}
+
+/// Remove generated file `target`, and forget it in `manifest`.
+fn remove_output(target: &Path, out_root: Option<&Path>, manifest: &mut Manifest) -> Result<()> {
+    match out_root {
+        Some(root) if target.starts_with(root) => output::remove_within(root, target)?,
+        _ => fs::remove_file(target)
+            .wrap_err_with(|| format!("Failed to remove {}", target.display()))?,
+    }
+    let template = manifest.remove(target);
+    spinner::println(format!("{EMOJI_REMOVE}Removed {}", target.display()));
+    events::emit(&Event::OutputRemoved {
+        target: &target.to_string_lossy(),
+        template: template.as_deref().map(Path::to_string_lossy).as_deref(),
+    });
+    Ok(())
 }
 
-fn walker(path: &Path) -> Result<Walk> {
-    let mut builder = WalkBuilder::new(path);
+/// Remove generated files whose templates are gone, and return how many were removed.
+fn clean(
+    template_root: &Path,
+    out_dir: Option<&Path>,
+    config: &Config,
+    templates: &[Template],
+    manifest: &mut Manifest,
+) -> Result<usize> {
+    let orphans = find_orphans(template_root, out_dir, config, templates, manifest)?;
+    for orphan in &orphans {
+        remove_output(&orphan.target, out_dir, manifest)?;
+    }
+    Ok(orphans.len())
+}
 
-    let mut types_builder = TypesBuilder::new();
-    types_builder.add("tmpl", "*.tmpl.nix")?;
-    types_builder.select("tmpl");
-    let types = types_builder.build()?;
+/// Make sure no hand-written file would be overwritten by `rendered` templates, unless `force`.
+///
+/// Files recorded in `manifest`, starting with the header of their template, or already having
+/// the rendered content may be overwritten.
+fn check_overwrites(
+    templates: &[Template],
+    rendered: &[String],
+    manifest: &Manifest,
+    force: bool,
+) -> Result<()> {
+    if force {
+        return Ok(());
+    }
+    let mut conflicts = vec![];
+    for (template, rendered) in templates.iter().zip(rendered) {
+        let target = &template.target;
+        let generated = template
+            .header
+            .as_deref()
+            .is_some_and(|header| output::is_generated(target, header));
+        if target.exists()
+            && !manifest.contains(&output::resolve(target)?)
+            && !generated
+            && fs::read(target).ok().as_deref() != Some(rendered.as_bytes())
+        {
+            conflicts.push(target.display().to_string());
+        }
+    }
+    if !conflicts.is_empty() {
+        bail!(
+            "Refusing to overwrite files not generated by nix-template: {}. Use `--force` to overwrite them anyway",
+            conflicts.join(", ")
+        );
+    }
+    Ok(())
+}
 
-    builder.types(types);
-    Ok(builder.build())
+/// Render a template into the content of its generated file.
+fn render(
+    env: &Environment,
+    source_path: &Path,
+    header: Option<&str>,
+    vars: &serde_json::Value,
+) -> Result<String> {
+    let rendered = env
+        .get_template(&source_path.to_string_lossy())?
+        .render(vars)?;
+    Ok(match header {
+        Some(header) => format::with_header(header, &rendered),
+        None => rendered,
+    })
 }
 
 fn main() -> Result<()> {
     pretty_env_logger::init();
     color_eyre::install()?;
     let args = Args::parse();
-    let command = args.command.unwrap_or_default();
+    if args.message_format == MessageFormat::Json {
+        // Rendered templates are printed to stdout.
+        let to_stderr = matches!(args.command, Some(Commands::Render { .. }));
+        events::enable(to_stderr);
+    }
+    let result = run(&args);
+    if let Err(e) = &result {
+        events::emit_error(e);
+    }
+    result
+}
+
+fn run(args: &Args) -> Result<()> {
+    let command = args.command.clone().unwrap_or_default();
+    if args.frozen && matches!(command, Commands::Update { .. }) {
+        bail!("Cannot update the lock file when it's frozen");
+    }
+    if args.frozen && command == Commands::Gc {
+        bail!("Cannot garbage collect the lock file when it's frozen");
+    }
+    if args.prune {
+        if command != Commands::Instantiate {
+            bail!("`--prune` can only be used when instantiating");
+        }
+        if args.frozen {
+            bail!("Cannot prune the lock file when it's frozen");
+        }
+    }
+    // A single template is looked up on its own, instead of in the template directory.
+    let (template_dir, stdin) = match &command {
+        Commands::Render { file, .. } if file == Path::new("-") => (Path::new("."), true),
+        Commands::Render { file, .. } => (
+            file.parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new(".")),
+            false,
+        ),
+        _ => (args.path.as_path(), false),
+    };
+    // Flags given on the command line take precedence over the config.
+    let config = Config::discover(template_dir)?;
+    let lock = args
+        .lock
+        .clone()
+        .or_else(|| config.lock())
+        .unwrap_or_else(|| PathBuf::from("template.lock"));
+    let jobs = args.jobs.or(config.jobs);
+    // Other commands never look at generated files. The directory might not exist yet, in which
+    // case all files in it are missing.
+    let out_root = match args.out_dir.clone().or_else(|| config.out_dir()) {
+        Some(dir)
+            if matches!(
+                command,
+                Commands::Instantiate | Commands::Check | Commands::Clean
+            ) =>
+        {
+            if command == Commands::Instantiate {
+                fs::create_dir_all(&dir)?;
+            }
+            Some(fs::canonicalize(&dir).or_else(|_| output::resolve(&dir))?)
+        }
+        _ => None,
+    };
+    // In check and outdated mode, a missing field is always an error.
+    let frozen = args.frozen
+        || (config.frozen
+            && !args.no_frozen
+            && matches!(command, Commands::Instantiate | Commands::Render { .. }))
+        || matches!(command, Commands::Check | Commands::Outdated { .. });
 
     let mut env = Environment::new();
-    let source = Source::from_path(&args.path);
+    let source = if stdin {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content)?;
+        let mut source = Source::new();
+        source.add_template(STDIN_TEMPLATE, content)?;
+        source
+    } else {
+        Source::from_path(template_dir)
+    };
     env.set_source(source);
     populate_environment(&mut env);
+    let context = vars::Context::new(&args.vars_file, args.env, &args.var)?;
 
-    let store = FileStore::with(
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&args.lock)?,
-        match command {
-            // In instantiate mode, we use cached values in lock file as much as possible.
-            // If a field is not present, we populate it with the current value.
-            Commands::Instantiate => true,
-            // In update mode, we always update the lock file with the current values.
-            Commands::Update => false,
-        },
-    )?;
+    // Frozen lock files are never written nor created.
+    let lock_file = store::open_lock_file(&lock, !frozen)?;
+    let mut selected_files = vec![];
+    let store = match &command {
+        // In instantiate and check mode, we use cached values in lock file as much as possible.
+        // If a field is not present, we populate it with the current value.
+        Commands::Instantiate
+        | Commands::Check
+        | Commands::Gc
+        | Commands::Render { .. }
+        | Commands::Clean
+        | Commands::Outdated { .. } => FileStore::with(&lock, lock_file, true)?,
+        // In update mode, we always update the lock file with the current values.
+        Commands::Update { only, file } if only.is_empty() && file.is_empty() => {
+            FileStore::with(&lock, lock_file, false)?
+        }
+        // Unless filters are given, in which case only matching values are updated.
+        Commands::Update { only, file } => {
+            let mut globs = GlobSetBuilder::new();
+            for pattern in only {
+                globs.add(Glob::new(pattern)?);
+            }
+            let globs = globs.build()?;
+            for path in file {
+                selected_files.push(
+                    fs::canonicalize(path)
+                        .wrap_err_with(|| format!("Template {} not found", path.display()))?,
+                );
+            }
+            let match_all = only.is_empty();
+            FileStore::with(&lock, lock_file, true)?
+                .refresh(move |path| match_all || globs.is_match(path.join("/")))
+        }
+    }
+    // Garbage collection only removes entries, so it never fetches missing ones.
+    .freeze(frozen || command == Commands::Gc);
     set_global_store(Arc::new(store.clone()));
 
-    new_global_progress_bar(ProgressBar::new_spinner);
+    // Events replace human-readable progress.
+    if !events::enabled() {
+        spinner::new_global_reporter();
+    }
 
-    for file in walker(&args.path)? {
-        let file = file?;
-        if file.path().is_dir() {
-            continue;
-        }
-        with_global_progress_bar(|pb| {
-            pb.println(format!(
-                "{EMOJI_ROCKET}Processing {}",
-                file.path().display()
-            ))
-        });
-        let source_path = file.path().strip_prefix(".")?;
-        let target_path = file.path().with_file_name(format!(
-            "{}.nix",
+    if let Commands::Render { file, ext } = &command {
+        let name = if stdin {
+            STDIN_TEMPLATE.to_string()
+        } else {
             file.file_name()
+                .ok_or_else(|| eyre!("Invalid template {}", file.display()))?
                 .to_string_lossy()
-                .trim_end_matches(".tmpl.nix")
+                .into_owned()
+        };
+        let ext = ext
+            .clone()
+            .or_else(|| format::target_extension(&name).map(ToString::to_string))
+            .unwrap_or_else(|| "nix".to_string());
+        env.set_auto_escape_callback(move |_| format::auto_escape(Some(&ext)));
+
+        let template_vars = context.for_template((!stdin).then_some(file.as_path()))?;
+        let _guard = store::enter_template(&name);
+        let rendered =
+            render(&env, Path::new(&name), None, &template_vars).wrap_err_with(|| {
+                format!(
+                    "Failed to render {} with lock file {}",
+                    file.display(),
+                    lock.display()
+                )
+            })?;
+        spinner::suspend(|| print!("{rendered}"));
+
+        delete_global_store();
+        if !frozen {
+            store.persist()?;
+        }
+        spinner::delete_global_reporter();
+        return Ok(());
+    }
+
+    let mut templates = find_templates(&args.path, &config, out_root.as_deref())?;
+    if !selected_files.is_empty() {
+        let mut selected = vec![];
+        let mut matched = HashSet::new();
+        for template in templates {
+            let path = fs::canonicalize(&template.path)?;
+            if selected_files.contains(&path) {
+                matched.insert(path);
+                selected.push(template);
+            }
+        }
+        // Otherwise a typo would silently update nothing.
+        let unmatched: Vec<_> = selected_files
+            .iter()
+            .filter(|path| !matched.contains(*path))
+            .map(|path| path.display().to_string())
+            .collect();
+        if !unmatched.is_empty() {
+            bail!("No template matches `--file` {}", unmatched.join(", "));
+        }
+        templates = selected;
+    }
+
+    // The manifest is written even when the lock file is frozen, so it lives in its own file.
+    let manifest_path = Manifest::path_for(&lock);
+    let mut manifest = Manifest::load(&manifest_path)?;
+    manifest.forget_missing();
+    // Orphans are looked for in the template directory and the output directory.
+    let template_root = fs::canonicalize(&args.path)?;
+
+    if command == Commands::Clean {
+        let removed = clean(
+            &template_root,
+            out_root.as_deref(),
+            &config,
+            &templates,
+            &mut manifest,
+        )?;
+        spinner::println(format!(
+            "{EMOJI_REMOVE}Removed {removed} orphaned generated file(s)"
         ));
+        manifest.save(&manifest_path)?;
+
+        delete_global_store();
+        if !frozen {
+            store.persist()?;
+        }
+        spinner::delete_global_reporter();
+        return Ok(());
+    }
 
-        match command {
+    // Escaping depends on the format of generated files.
+    let extensions: HashMap<String, String> = templates
+        .iter()
+        .filter_map(|template| {
+            let name = template.path.strip_prefix(&args.path).ok()?;
+            let ext = template.target.extension()?;
+            Some((
+                name.to_string_lossy().into_owned(),
+                ext.to_string_lossy().into_owned(),
+            ))
+        })
+        .collect();
+    env.set_auto_escape_callback(move |name| {
+        format::auto_escape(
+            extensions
+                .get(name)
+                .map(String::as_str)
+                .or_else(|| format::target_extension(name)),
+        )
+    });
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs.unwrap_or(0))
+        .build()?;
+    // Templates are rendered in parallel, but results are handled in order.
+    let rendered = pool.install(|| {
+        templates
+            .par_iter()
+            .map(|Template { path, header, .. }| {
+                spinner::println(format!("{EMOJI_ROCKET}Processing {}", path.display()));
+                let source_path = path.strip_prefix(&args.path)?;
+                let template = source_path.to_string_lossy();
+                events::emit(&Event::TemplateStarted {
+                    template: &template,
+                });
+                let template_vars = context.for_template(Some(path))?;
+                let _guard = store::enter_template(&template);
+                render(&env, source_path, header.as_deref(), &template_vars).wrap_err_with(|| {
+                    format!(
+                        "Failed to render {} with lock file {}",
+                        source_path.display(),
+                        lock.display()
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+    });
+    let rendered = if command == Commands::Gc {
+        rendered.wrap_err("Cannot tell which lock entries are used, instantiate templates first")?
+    } else {
+        rendered?
+    };
+
+    let mut stale = 0;
+    let mut summary = output::Summary::default();
+    // All targets are checked before any is written, so that nothing is left half done.
+    if command == Commands::Instantiate {
+        check_overwrites(&templates, &rendered, &manifest, args.force)?;
+    }
+
+    let mut generated = HashSet::new();
+    for (template, rendered) in templates.iter().zip(rendered) {
+        let source_path = template.path.strip_prefix(&args.path)?;
+        let target_path = &template.target;
+
+        let (outcome, diff) = match command {
             Commands::Instantiate => {
                 // We write to file if only in instantiate mode.
-                let mut file = fs::File::create(&target_path)?;
-                writeln!(file, "# GENERATED BY nix-template. DO NOT EDIT.")?;
-                env.get_template(&source_path.to_string_lossy())?
-                    .render_to_write(context!(), file)?;
+                if let Some(root) = &out_root {
+                    output::check_within(root, target_path)?;
+                    if let Some(dir) = target_path.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                }
+                let outcome = output::write_if_changed(target_path, &rendered)?;
+                summary.record(outcome);
+                let target = output::resolve(target_path)?;
+                manifest.insert(target.clone(), fs::canonicalize(&template.path)?);
+                generated.insert(target);
+                (Some(outcome.as_str()), None)
+            }
+            Commands::Update { .. }
+            | Commands::Gc
+            | Commands::Render { .. }
+            | Commands::Clean
+            | Commands::Outdated { .. } => {
+                // We don't write to file in update, gc and outdated mode.
+                (None, None)
+            }
+            Commands::Check => {
+                let existing = fs::read_to_string(target_path).unwrap_or_default();
+                if existing == rendered {
+                    (Some("fresh"), None)
+                } else {
+                    stale += 1;
+                    // Both sides are the generated file, before and after rendering.
+                    let target = target_path.strip_prefix(".").unwrap_or(target_path);
+                    let diff = TextDiff::from_lines(&existing, &rendered)
+                        .unified_diff()
+                        .header(
+                            &Path::new("a").join(target).to_string_lossy(),
+                            &Path::new("b").join(target).to_string_lossy(),
+                        )
+                        .to_string();
+                    if !events::enabled() {
+                        spinner::suspend(|| print!("{diff}"));
+                    }
+                    (Some("stale"), Some(diff))
+                }
             }
-            Commands::Update => {
-                // We don't write to file in update mode.
-                env.get_template(&source_path.to_string_lossy())?
-                    .render_to_write(context!(), &mut std::io::sink())?;
+        };
+        events::emit(&Event::TemplateFinished {
+            template: &source_path.to_string_lossy(),
+            target: &target_path.to_string_lossy(),
+            outcome,
+            diff: diff.as_deref(),
+        });
+    }
+
+    delete_global_store();
+    if command == Commands::Instantiate {
+        // Files in the output directory are all ours, so those of removed templates can go.
+        if let Some(root) = &out_root {
+            let removed: Vec<_> = manifest
+                .iter()
+                .filter(|(target, _)| {
+                    !generated.contains(*target) && output::check_within(root, target).is_ok()
+                })
+                .map(|(target, _)| target.to_path_buf())
+                .collect();
+            for target in removed {
+                remove_output(&target, Some(root), &mut manifest)?;
+            }
+        }
+        // Others might still be wanted, so it's up to the user to remove them.
+        let orphans = find_orphans(
+            &template_root,
+            out_root.as_deref(),
+            &config,
+            &templates,
+            &manifest,
+        )?;
+        for orphan in &orphans {
+            spinner::println(format!(
+                "{EMOJI_WARN}Orphaned generated file {}, run `nix-template clean` to remove it",
+                orphan.target.display()
+            ));
+            events::emit(&Event::Orphaned {
+                target: &orphan.target.to_string_lossy(),
+                template: orphan
+                    .template
+                    .as_deref()
+                    .map(Path::to_string_lossy)
+                    .as_deref(),
+            });
+        }
+        manifest.save(&manifest_path)?;
+        spinner::println(format!("{EMOJI_DONE}Generated files: {summary}"));
+    }
+    if let Commands::Outdated { json } = command {
+        // Only entries used by current templates are checked.
+        let accessed = store.accessed();
+        let entries = outdated::lock_entries(&store.snapshot())
+            .into_iter()
+            .filter(|entry| accessed.contains_key(&entry.path))
+            .collect();
+        spinner::println(format!("{EMOJI_SEARCH}Checking upstream values..."));
+        let outdated = outdated::find_outdated(entries, &accessed);
+        spinner::delete_global_reporter();
+        if events::enabled() {
+            for item in &outdated {
+                events::emit(&Event::Outdated {
+                    key: &item.key,
+                    locked: &item.locked,
+                    upstream: &item.upstream,
+                    templates: item.templates.iter().map(String::as_str).collect(),
+                });
             }
+        } else if json {
+            println!("{}", serde_json::to_string_pretty(&outdated)?);
+        } else if outdated.is_empty() {
+            println!("All locked values are up to date");
+        } else {
+            print!("{}", outdated::format_table(&outdated));
         }
+        return Ok(());
+    }
+    if stale > 0 {
+        spinner::delete_global_reporter();
+        bail!("{stale} generated file(s) are out of date");
+    }
+    if frozen {
+        // Nothing could have been changed.
+        spinner::delete_global_reporter();
+        return Ok(());
+    }
+
+    if args.prune || command == Commands::Gc {
+        let removed = store.prune();
+        for path in &removed {
+            spinner::println(format!("{EMOJI_REMOVE}Removed {}", path.join("/")));
+        }
+        spinner::println(format!(
+            "{EMOJI_REMOVE}Removed {} unused lock entries",
+            removed.len()
+        ));
     }
 
     // All templates has been rendered, we can now save the lock file.
     // Need to remove the global store to make sure we have a unique reference to the store.
-    with_global_progress_bar(|pb| pb.println(format!("{EMOJI_WRITE}Writing lock file...")));
-    delete_global_store();
+    spinner::println(format!("{EMOJI_WRITE}Writing lock file..."));
     store.persist()?;
 
-    delete_global_progress_bar();
+    spinner::delete_global_reporter();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::config::Config;
+    use crate::manifest::Manifest;
+    use crate::{check_overwrites, clean, find_templates};
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn must_clean_orphaned_outputs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().canonicalize().unwrap();
+        let out_dir = root.join("out");
+        let src = root.join("src");
+        let config = Config::discover(&root).unwrap();
+        let mut manifest = Manifest::default();
+
+        // Generated files of existing templates are kept.
+        write(&src.join("a.tmpl.nix"), "{}");
+        write(
+            &out_dir.join("a.nix"),
+            "# GENERATED BY nix-template. DO NOT EDIT.\n{}",
+        );
+        // Orphans recorded in the manifest are found without any header.
+        write(&out_dir.join("b.json"), "{}");
+        manifest.insert(out_dir.join("b.json"), src.join("b.tmpl.json"));
+        // Orphans are recognized by their header, in the template and output directories.
+        write(
+            &src.join("c.sh"),
+            "#!/bin/sh\n# GENERATED BY nix-template. DO NOT EDIT.\n",
+        );
+        write(
+            &out_dir.join("d/e.nix"),
+            "# GENERATED BY nix-template. DO NOT EDIT.\n{}",
+        );
+        // Hand-written files are left alone, even when mentioning the header.
+        write(&src.join("f.json"), "{}");
+        write(
+            &src.join("g.nix"),
+            "# This is not GENERATED BY nix-template. DO NOT EDIT. the license\n{}",
+        );
+        write(
+            &src.join("h.nix"),
+            "{}\n# GENERATED BY nix-template. DO NOT EDIT.\n",
+        );
+
+        let templates = find_templates(&src, &config, Some(&out_dir)).unwrap();
+        let removed = clean(&src, Some(&out_dir), &config, &templates, &mut manifest).unwrap();
+        assert_eq!(removed, 3);
+        assert!(out_dir.join("a.nix").exists());
+        assert!(!out_dir.join("b.json").exists());
+        assert!(!manifest.contains(&out_dir.join("b.json")));
+        assert!(!src.join("c.sh").exists());
+        assert!(!out_dir.join("d").exists());
+        assert!(src.join("f.json").exists());
+        assert!(src.join("g.nix").exists());
+        assert!(src.join("h.nix").exists());
+    }
+
+    #[test]
+    fn must_skip_symlinks_out_of_project() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().canonicalize().unwrap();
+        let config = Config::discover(&root).unwrap();
+        write(&root.join("a.tmpl.nix"), "{}");
+        std::os::unix::fs::symlink("/nonexistent", root.join("result")).unwrap();
+        std::os::unix::fs::symlink("/etc/hostname", root.join("link")).unwrap();
+
+        let templates = find_templates(&root, &config, None).unwrap();
+        assert_eq!(templates.len(), 1);
+        assert_eq!(templates[0].target, root.join("a.nix"));
+    }
+
+    #[test]
+    fn must_refuse_to_overwrite_hand_written_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().canonicalize().unwrap();
+        let config = Config::discover(&root).unwrap();
+        write(&root.join("a.tmpl.json"), "{}");
+        write(&root.join("b.tmpl.nix"), "{}");
+        let mut templates = find_templates(&root, &config, None).unwrap();
+        templates.sort_by(|a, b| a.path.cmp(&b.path));
+        let rendered = [
+            "{}".to_string(),
+            "# GENERATED BY nix-template. DO NOT EDIT.\n{}".to_string(),
+        ];
+        let mut manifest = Manifest::default();
+        let check = |manifest: &Manifest, force| {
+            check_overwrites(&templates, &rendered, manifest, force).is_ok()
+        };
+
+        // Missing files, and files with the expected header or content, are fine.
+        assert!(check(&manifest, false));
+        write(&root.join("a.json"), "{}");
+        write(
+            &root.join("b.nix"),
+            "# GENERATED BY nix-template. DO NOT EDIT.\n{ old = 1; }",
+        );
+        assert!(check(&manifest, false));
+
+        // Hand-written files are refused, unless forced.
+        write(&root.join("a.json"), r#"{"a": 1}"#);
+        write(
+            &root.join("b.nix"),
+            "# GENERATED BY nix-template. DO NOT EDIT. or do\n{}",
+        );
+        let err = check_overwrites(&templates, &rendered, &manifest, false).unwrap_err();
+        assert!(err.to_string().contains("a.json"));
+        assert!(err.to_string().contains("b.nix"));
+        assert!(check(&manifest, true));
+
+        // Files recorded in the manifest are generated, even without a header.
+        manifest.insert(root.join("a.json"), root.join("a.tmpl.json"));
+        manifest.insert(root.join("b.nix"), root.join("b.tmpl.nix"));
+        assert!(check(&manifest, false));
+    }
+}
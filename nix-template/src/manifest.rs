@@ -0,0 +1,205 @@
+//! Manifest of generated files, kept next to the lock file to find outputs whose templates are
+//! gone.
+//!
+//! It's kept apart from the lock file, because it must be updated even when the lock file is
+//! frozen.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use eyre::WrapErr;
+
+use crate::output;
+use crate::Result;
+
+/// Generated files and their templates.
+///
+/// Paths are absolute in memory, and relative to the directory of the manifest when stored, so
+/// that it can be moved along with the project.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    base: PathBuf,
+    entries: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl Manifest {
+    /// Path of the manifest belonging to `lock`.
+    pub fn path_for(lock: &Path) -> PathBuf {
+        lock.with_extension("outputs.json")
+    }
+
+    /// Load the manifest at `path`, which may not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let base = fs::canonicalize(dir)?;
+        let entries = match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .wrap_err_with(|| format!("Invalid manifest {}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self::new(&base, entries))
+    }
+
+    /// Write the manifest to `path` if changed, or remove it if there's nothing to record.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if self.entries.is_empty() {
+            return match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let content = serde_json::to_string_pretty(&self.to_entries())? + "\n";
+        output::write_if_changed(path, &content)
+            .wrap_err_with(|| format!("Failed to write manifest {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Resolve stored `entries` against `base`, the canonical directory of the manifest.
+    pub fn new(base: &Path, entries: BTreeMap<String, String>) -> Self {
+        let entries = entries
+            .into_iter()
+            .map(|(target, template)| {
+                (
+                    normalize(&base.join(target)),
+                    normalize(&base.join(template)),
+                )
+            })
+            .collect();
+        Self {
+            base: base.to_path_buf(),
+            entries,
+        }
+    }
+
+    /// Record that `target` is generated from `template`.
+    pub fn insert(&mut self, target: PathBuf, template: PathBuf) {
+        self.entries.insert(target, template);
+    }
+
+    pub fn contains(&self, target: &Path) -> bool {
+        self.entries.contains_key(target)
+    }
+
+    /// Forget generated files which no longer exist.
+    pub fn forget_missing(&mut self) {
+        self.entries.retain(|target, _| target.exists());
+    }
+
+    pub fn remove(&mut self, target: &Path) -> Option<PathBuf> {
+        self.entries.remove(target)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &Path)> {
+        self.entries
+            .iter()
+            .map(|(target, template)| (target.as_path(), template.as_path()))
+    }
+
+    /// Entries to be stored in the manifest file.
+    fn to_entries(&self) -> BTreeMap<String, String> {
+        self.entries
+            .iter()
+            .map(|(target, template)| {
+                (
+                    relative_to(target, &self.base)
+                        .to_string_lossy()
+                        .into_owned(),
+                    relative_to(template, &self.base)
+                        .to_string_lossy()
+                        .into_owned(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Remove `.` and `..` from `path` without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Express absolute `path` relative to absolute `base`, going up with `..` if needed.
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    base.components()
+        .skip(common)
+        .map(|_| Component::ParentDir)
+        .chain(path.components().skip(common))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::manifest::Manifest;
+
+    #[test]
+    fn must_resolve_entries_against_lock_dir() {
+        let entries = BTreeMap::from([
+            ("out/a.nix".to_string(), "src/a.tmpl.nix".to_string()),
+            ("../b.nix".to_string(), "../b.tmpl.nix".to_string()),
+        ]);
+        let mut manifest = Manifest::new(Path::new("/repo/nix"), entries.clone());
+        assert_eq!(
+            manifest.iter().collect::<Vec<_>>(),
+            vec![
+                (Path::new("/repo/b.nix"), Path::new("/repo/b.tmpl.nix")),
+                (
+                    Path::new("/repo/nix/out/a.nix"),
+                    Path::new("/repo/nix/src/a.tmpl.nix")
+                ),
+            ]
+        );
+        assert_eq!(manifest.to_entries(), entries);
+
+        manifest.insert(PathBuf::from("/c.nix"), PathBuf::from("/c.tmpl.nix"));
+        assert_eq!(manifest.to_entries()["../../c.nix"], "../../c.tmpl.nix");
+        assert!(manifest.remove(Path::new("/repo/b.nix")).is_some());
+    }
+
+    #[test]
+    fn must_save_and_load_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        let path = Manifest::path_for(&dir.join("template.lock"));
+        assert_eq!(path, dir.join("template.outputs.json"));
+
+        let mut manifest = Manifest::load(&path).unwrap();
+        assert_eq!(manifest.iter().count(), 0);
+        manifest.insert(dir.join("a.json"), dir.join("a.tmpl.json"));
+        manifest.save(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "{\n  \"a.json\": \"a.tmpl.json\"\n}\n"
+        );
+
+        let mut manifest = Manifest::load(&path).unwrap();
+        assert!(manifest.contains(&dir.join("a.json")));
+        // Nothing is left behind once all generated files are gone.
+        manifest.forget_missing();
+        manifest.save(&path).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,178 @@
+//! Nix archive (NAR) serialization.
+//!
+//! This is a pure-Rust reimplementation of `nix-store --dump` and `nix hash path`, so that we can
+//! calculate hashes on machines without a nix installation.
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use eyre::{bail, WrapErr};
+use sha2::{Digest, Sha256};
+
+use crate::Result;
+
+const NAR_MAGIC: &str = "nix-archive-1";
+
+/// Serialize the file system object at `path` into NAR format.
+pub fn dump_path(path: &Path, sink: &mut impl Write) -> Result<()> {
+    write_str(sink, NAR_MAGIC.as_bytes())?;
+    dump_node(path, sink)
+}
+
+/// Returns the sha256 digest of the NAR serialization of `path`.
+///
+/// This is equivalent to `nix hash path --type sha256 <path>`.
+pub fn hash_path(path: &Path) -> Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    dump_path(path, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+fn dump_node(path: &Path, sink: &mut impl Write) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)
+        .wrap_err_with(|| format!("Failed to stat {}", path.display()))?;
+    let file_type = metadata.file_type();
+
+    write_str(sink, b"(")?;
+    write_str(sink, b"type")?;
+    if file_type.is_file() {
+        write_str(sink, b"regular")?;
+        // Nix only preserves the executable bit of the owner.
+        if metadata.permissions().mode() & 0o100 != 0 {
+            write_str(sink, b"executable")?;
+            write_str(sink, b"")?;
+        }
+        write_str(sink, b"contents")?;
+        let mut file =
+            File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+        write_u64(sink, metadata.len())?;
+        let copied = io::copy(&mut file, sink)?;
+        if copied != metadata.len() {
+            bail!("File {} changed while being serialized", path.display());
+        }
+        write_padding(sink, copied)?;
+    } else if file_type.is_symlink() {
+        write_str(sink, b"symlink")?;
+        write_str(sink, b"target")?;
+        write_str(sink, fs::read_link(path)?.as_os_str().as_bytes())?;
+    } else if file_type.is_dir() {
+        write_str(sink, b"directory")?;
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Entries must be sorted by their raw bytes.
+        entries.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        for name in entries {
+            write_str(sink, b"entry")?;
+            write_str(sink, b"(")?;
+            write_str(sink, b"name")?;
+            write_str(sink, name.as_bytes())?;
+            write_str(sink, b"node")?;
+            dump_node(&path.join(&name), sink)?;
+            write_str(sink, b")")?;
+        }
+    } else {
+        bail!("Unsupported file type: {}", path.display());
+    }
+    write_str(sink, b")")?;
+    Ok(())
+}
+
+fn write_u64(sink: &mut impl Write, n: u64) -> io::Result<()> {
+    sink.write_all(&n.to_le_bytes())
+}
+
+fn write_padding(sink: &mut impl Write, len: u64) -> io::Result<()> {
+    let padding = (8 - len % 8) % 8;
+    #[allow(clippy::cast_possible_truncation)]
+    sink.write_all(&[0; 8][..padding as usize])
+}
+
+fn write_str(sink: &mut impl Write, s: &[u8]) -> io::Result<()> {
+    write_u64(sink, s.len() as u64)?;
+    sink.write_all(s)?;
+    write_padding(sink, s.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
+
+    use crate::nar::hash_path;
+    use crate::testing::{nested_dirs, write_file, NESTED_DIRS_NAR_HASH};
+
+    // Golden values are sha256 hashes of NARs generated by `nix-store --dump`.
+
+    #[test]
+    fn must_hash_files() {
+        let dir = TempDir::new().unwrap();
+
+        let empty = dir.path().join("empty");
+        write_file(&empty, "", false);
+        assert_eq!(
+            base64::encode(hash_path(&empty).unwrap()),
+            "d6xi4mKdjkX2JFicDIv5niSzpyI0m/Hnm8GGAIU04kY="
+        );
+
+        let small = dir.path().join("small");
+        write_file(&small, "This is a test file.\n", false);
+        assert_eq!(
+            base64::encode(hash_path(&small).unwrap()),
+            "wOHoCt7lnw040oZj9OUwZNVtP2/OSUsCLEKjItqcl4g="
+        );
+
+        let executable = dir.path().join("executable");
+        write_file(&executable, "", true);
+        assert_eq!(
+            base64::encode(hash_path(&executable).unwrap()),
+            "NOALhZKmrUZYUaRqZ0ZOB2EC/VEGymyzOi8VAJ0w1ZA="
+        );
+    }
+
+    #[test]
+    fn must_hash_symlinks() {
+        let dir = TempDir::new().unwrap();
+        let link = dir.path().join("link");
+        symlink("02-empty-file.in", &link).unwrap();
+        assert_eq!(
+            base64::encode(hash_path(&link).unwrap()),
+            "FXfH9HbN6/tVlR8QzN06GtutQPN3IAgHx4TmxPhn8nM="
+        );
+    }
+
+    #[test]
+    fn must_hash_directories() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(
+            base64::encode(hash_path(dir.path()).unwrap()),
+            "pQpattmS9VmO3ZIQUFn66az8GSmB4IvYhTTCFn6SUmo="
+        );
+
+        let dir = TempDir::new().unwrap();
+        write_file(&dir.path().join("an-empty-file"), "", false);
+        assert_eq!(
+            base64::encode(hash_path(dir.path()).unwrap()),
+            "FpDXmaojsp3YL+5KWT/8OVSIxn/tCCgf1qyHcvlEaiw="
+        );
+
+        let dir = TempDir::new().unwrap();
+        nested_dirs(dir.path(), false);
+        assert_eq!(
+            base64::encode(hash_path(dir.path()).unwrap()),
+            "PBUsQI/FFziIZ+Lc+SLoeXcYEEMVtgIiE0iuOk2xZnw="
+        );
+
+        let dir = TempDir::new().unwrap();
+        nested_dirs(dir.path(), true);
+        assert_eq!(
+            base64::encode(hash_path(dir.path()).unwrap()),
+            NESTED_DIRS_NAR_HASH
+        );
+    }
+}
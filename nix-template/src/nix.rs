@@ -0,0 +1,224 @@
+//! Escaping of values spliced into nix source.
+use std::fmt::Write;
+
+use minijinja::value::Value;
+use minijinja::{AutoEscape, Environment, Error, ErrorKind, Output, State};
+
+/// Auto escaping of nix templates, for values spliced into double-quoted strings.
+pub const AUTO_ESCAPE: AutoEscape = AutoEscape::Custom("nix");
+
+const KEYWORDS: &[&str] = &[
+    "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with",
+];
+
+/// Escape `s` to be put into a nix double-quoted string.
+pub fn escape_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => escaped.push_str("\\$"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escape `s` to be put into a nix indented string.
+pub fn escape_indented_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                escaped.push_str("'''");
+            }
+            // A lone quote would merge with a following `''`, including the closing one.
+            '\'' if matches!(chars.peek(), None | Some('$' | '\r')) => escaped.push_str("''\\'"),
+            '$' if chars.peek() == Some(&'{') => escaped.push_str("''$"),
+            '\r' => escaped.push_str("''\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn attr_name(key: &str) -> String {
+    let mut chars = key.chars();
+    let is_ident = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-'));
+    if is_ident && !KEYWORDS.contains(&key) {
+        key.to_string()
+    } else {
+        format!("\"{}\"", escape_string(key))
+    }
+}
+
+/// Convert `value` into a nix expression.
+pub fn to_expr(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "null".to_string(),
+        serde_json::Value::Bool(b) => b.to_string(),
+        serde_json::Value::Number(n) => n.to_string(),
+        serde_json::Value::String(s) => format!("\"{}\"", escape_string(s)),
+        serde_json::Value::Array(items) if items.is_empty() => "[ ]".to_string(),
+        serde_json::Value::Array(items) => {
+            let items: Vec<_> = items.iter().map(to_expr).collect();
+            format!("[ {} ]", items.join(" "))
+        }
+        serde_json::Value::Object(attrs) if attrs.is_empty() => "{ }".to_string(),
+        serde_json::Value::Object(attrs) => {
+            let mut buffer = "{ ".to_string();
+            for (key, value) in attrs {
+                write!(buffer, "{} = {}; ", attr_name(key), to_expr(value)).unwrap();
+            }
+            buffer.push('}');
+            buffer
+        }
+    }
+}
+
+fn to_json(value: &Value) -> Result<serde_json::Value, Error> {
+    serde_json::to_value(value).map_err(|e| {
+        Error::new(ErrorKind::BadSerialization, "unable to convert to nix").with_source(e)
+    })
+}
+
+fn to_str(value: &Value) -> String {
+    value
+        .as_str()
+        .map_or_else(|| value.to_string(), ToString::to_string)
+}
+
+/// Formats the value as a nix double-quoted string.
+fn nix_string(value: Value) -> Value {
+    Value::from_safe_string(format!("\"{}\"", escape_string(&to_str(&value))))
+}
+
+/// Formats the value as a nix indented string.
+fn nix_indented_string(value: Value) -> Value {
+    Value::from_safe_string(format!("''{}''", escape_indented_string(&to_str(&value))))
+}
+
+/// Formats a map as a nix attribute set.
+fn nix_attrset(value: Value) -> Result<Value, Error> {
+    match to_json(&value)? {
+        attrs @ serde_json::Value::Object(_) => Ok(Value::from_safe_string(to_expr(&attrs))),
+        _ => Err(Error::new(
+            ErrorKind::InvalidOperation,
+            "nix_attrset expects a map",
+        )),
+    }
+}
+
+/// Formats a sequence as a nix list.
+fn nix_list(value: Value) -> Result<Value, Error> {
+    match to_json(&value)? {
+        items @ serde_json::Value::Array(_) => Ok(Value::from_safe_string(to_expr(&items))),
+        _ => Err(Error::new(
+            ErrorKind::InvalidOperation,
+            "nix_list expects a sequence",
+        )),
+    }
+}
+
+fn formatter(out: &mut Output, state: &State, value: &Value) -> Result<(), Error> {
+    if state.auto_escape() == AUTO_ESCAPE && !value.is_safe() {
+        return out
+            .write_str(&escape_string(&to_str(value)))
+            .map_err(Error::from);
+    }
+    minijinja::escape_formatter(out, state, value)
+}
+
+/// Register nix escaping and filters.
+pub fn populate_environment(env: &mut Environment) {
+    env.set_formatter(formatter);
+    env.add_filter("nix_string", nix_string);
+    env.add_filter("nix_indented_string", nix_indented_string);
+    env.add_filter("nix_attrset", nix_attrset);
+    env.add_filter("nix_list", nix_list);
+}
+
+#[cfg(test)]
+mod tests {
+    use minijinja::{context, Environment};
+
+    use crate::nix::{populate_environment, AUTO_ESCAPE};
+
+    #[test]
+    fn must_escape_nix() {
+        let mut env = Environment::new();
+        populate_environment(&mut env);
+        env.set_auto_escape_callback(|name| {
+            if name.ends_with(".nix") {
+                AUTO_ESCAPE
+            } else {
+                minijinja::AutoEscape::None
+            }
+        });
+        env.add_template(
+            "a.nix",
+            r#"s = "{{ s }}"; t = "{{ s | safe }}"; {{ s | nix_string }} {{ s | nix_indented_string }}"#,
+        )
+        .unwrap();
+        env.add_template("b.nix", "{{ attrs | nix_attrset }} {{ items | nix_list }}")
+            .unwrap();
+        env.add_template("c.txt", "{{ s }}").unwrap();
+
+        let s = "a\"b\\c${d}$e\n''f";
+        assert_eq!(
+            env.get_template("a.nix")
+                .unwrap()
+                .render(context!(s))
+                .unwrap(),
+            r#"s = "a\"b\\c\${d}$e\n''f"; t = "a"b\c${d}$e
+''f"; "a\"b\\c\${d}$e\n''f" ''a"b\c''${d}$e
+'''f''"#
+        );
+        assert_eq!(
+            env.get_template("b.nix")
+                .unwrap()
+                .render(context!(
+                    attrs => maplit::btreemap! {
+                        "name" => minijinja::value::Value::from("x"),
+                        "with" => minijinja::value::Value::from(true),
+                        "a.b" => minijinja::value::Value::from(vec![1, 2]),
+                    },
+                    items => vec!["${x}"],
+                ))
+                .unwrap(),
+            r#"{ "a.b" = [ 1 2 ]; name = "x"; "with" = true; } [ "\${x}" ]"#
+        );
+        assert_eq!(
+            env.get_template("c.txt")
+                .unwrap()
+                .render(context!(s))
+                .unwrap(),
+            s
+        );
+        assert!(env.render_str("{{ 1 | nix_attrset }}", context!()).is_err());
+
+        for (s, expected) in [
+            ("it's", "''it's''"),
+            ("a'", "''a''\\'''"),
+            ("a''", "''a'''''"),
+            ("a'''", "''a'''''\\'''"),
+            ("'${x}", "''''\\'''${x}''"),
+        ] {
+            assert_eq!(
+                env.render_str("{{ s | nix_indented_string }}", context!(s))
+                    .unwrap(),
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Detection of locked values which have newer upstream values.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::sync::Arc;
+
+use console::Emoji;
+use log::warn;
+use serde::Serialize;
+
+use crate::spinner;
+use crate::store::{delete_global_store, set_global_store, NoopStore};
+use crate::CACHED_UTILS;
+
+const EMOJI_WARN: Emoji = Emoji("⚠️ ", "");
+
+/// A helper call recorded in the lock file.
+pub struct LockEntry {
+    pub path: Vec<String>,
+    pub value: serde_json::Value,
+}
+
+/// Enumerate all helper calls recorded in the lock file.
+pub fn lock_entries(data: &serde_json::Value) -> Vec<LockEntry> {
+    fn collect(
+        node: &serde_json::Value,
+        path: &mut Vec<String>,
+        depth: usize,
+        out: &mut Vec<LockEntry>,
+    ) {
+        if depth == 0 {
+            out.push(LockEntry {
+                path: path.clone(),
+                value: node.clone(),
+            });
+            return;
+        }
+        for (key, child) in node.as_object().into_iter().flatten() {
+            path.push(key.clone());
+            collect(child, path, depth - 1, out);
+            path.pop();
+        }
+    }
+
+    let mut entries = vec![];
+    for (key, node) in data.as_object().into_iter().flatten() {
+        if key == "version" {
+            continue;
+        }
+        // Values may be objects themselves, so we need the arity to tell where the path ends.
+        match CACHED_UTILS.iter().find(|(name, _, _)| name == key) {
+            Some((_, arity, _)) => collect(node, &mut vec![key.clone()], *arity, &mut entries),
+            None => warn!("Unknown helper in lock file: {}", key),
+        }
+    }
+    entries
+}
+
+#[derive(Serialize)]
+pub struct Outdated {
+    pub key: String,
+    pub locked: serde_json::Value,
+    pub upstream: serde_json::Value,
+    pub templates: BTreeSet<String>,
+}
+
+/// Re-evaluate all entries bypassing the cache, and return those whose upstream value differs.
+pub fn find_outdated(
+    entries: Vec<LockEntry>,
+    accessed: &BTreeMap<Vec<String>, BTreeSet<String>>,
+) -> Vec<Outdated> {
+    // Nested helper calls must not be served from the lock file either.
+    set_global_store(Arc::new(NoopStore));
+    let mut outdated = vec![];
+    for LockEntry { path, value } in entries {
+        let (_, _, func) = CACHED_UTILS
+            .iter()
+            .find(|(name, _, _)| *name == path[0])
+            .unwrap();
+        let key = path.join("/");
+        match func(&path[1..]) {
+            Ok(upstream) if upstream != value => outdated.push(Outdated {
+                key,
+                locked: value,
+                upstream,
+                templates: accessed.get(&path).cloned().unwrap_or_default(),
+            }),
+            Ok(_) => {}
+            Err(e) => spinner::println(format!("{EMOJI_WARN}Failed to check {key}: {e}")),
+        }
+    }
+    delete_global_store();
+    outdated
+}
+
+fn display_value(value: &serde_json::Value) -> String {
+    value
+        .as_str()
+        .map_or_else(|| value.to_string(), ToString::to_string)
+}
+
+/// Format outdated entries as a table.
+pub fn format_table(outdated: &[Outdated]) -> String {
+    let header = ["KEY", "LOCKED", "UPSTREAM", "TEMPLATES"].map(ToString::to_string);
+    let rows: Vec<[String; 4]> = outdated
+        .iter()
+        .map(|item| {
+            [
+                item.key.clone(),
+                display_value(&item.locked),
+                display_value(&item.upstream),
+                item.templates
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ]
+        })
+        .collect();
+
+    let mut widths = [0; 4];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut buffer = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(buffer, "{}", line.trim_end()).unwrap();
+    }
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::outdated::lock_entries;
+
+    #[test]
+    fn must_enumerate_lock_entries() {
+        let data = json!({
+            "version": 0,
+            "commit_of_git": {
+                "https://example.com/a.git": { "main": "aaa", "dev": "bbb" },
+            },
+            "git_src": {
+                "https://example.com/a.git": { "main": { "rev": "aaa", "hash": "sha256-" } },
+            },
+            "unknown_helper": { "x": "y" },
+        });
+        let entries: Vec<_> = lock_entries(&data)
+            .into_iter()
+            .map(|entry| (entry.path.join("/"), entry.value))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    "commit_of_git/https://example.com/a.git/dev".to_string(),
+                    json!("bbb")
+                ),
+                (
+                    "commit_of_git/https://example.com/a.git/main".to_string(),
+                    json!("aaa")
+                ),
+                (
+                    "git_src/https://example.com/a.git/main".to_string(),
+                    json!({ "rev": "aaa", "hash": "sha256-" })
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! Writing generated files.
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use eyre::{eyre, WrapErr};
+
+use crate::{format, Result};
+
+/// Replace the content of `path` atomically.
+///
+/// Content is written to a temporary file in the same directory which then replaces the target,
+/// so that an interrupted write never leaves a truncated file behind. Permissions of an existing
+/// target are kept, and new files are created with the default mode restricted by the umask.
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    // Replace the target instead of the symlink, if any.
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let existing = fs::metadata(&target).ok().map(|meta| meta.permissions());
+
+    // Like `File::create`, the umask applies to the mode given here.
+    let mut temp = tempfile::Builder::new()
+        .permissions(fs::Permissions::from_mode(0o666))
+        .tempfile_in(dir)?;
+    temp.write_all(content)?;
+    temp.as_file().sync_all()?;
+    if let Some(permissions) = existing {
+        fs::set_permissions(temp.path(), permissions)?;
+    }
+    temp.persist(&target)
+        .wrap_err_with(|| format!("Failed to write {}", target.display()))?;
+    Ok(())
+}
+
+/// Ensure `path` is inside `root`, also following symlinks of its existing ancestors.
+///
+/// `root` must be canonical.
+pub fn check_within(root: &Path, path: &Path) -> Result<()> {
+    let escaped = || {
+        eyre!(
+            "Refusing to write {} outside of the output directory {}",
+            path.display(),
+            root.display()
+        )
+    };
+    let relative = path.strip_prefix(root).map_err(|_| escaped())?;
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(escaped());
+    }
+    let existing = path.ancestors().find(|dir| dir.exists()).unwrap_or(root);
+    if !fs::canonicalize(existing)?.starts_with(root) {
+        return Err(escaped());
+    }
+    Ok(())
+}
+
+/// Absolute path of `path` with its parent canonicalized, so that it's comparable with other
+/// resolved paths even if it doesn't exist yet.
+pub fn resolve(path: &Path) -> Result<PathBuf> {
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) if dir.is_dir() => Ok(fs::canonicalize(dir)?.join(name)),
+        _ => Ok(std::path::absolute(path)?),
+    }
+}
+
+/// Whether file `path` starts with the line `header`.
+///
+/// Only the beginning of the file is read.
+pub fn is_generated(path: &Path, header: &str) -> bool {
+    let Ok(file) = fs::File::open(path) else {
+        return false;
+    };
+    let mut head = Vec::new();
+    // The header might come after a shebang.
+    let mut reader = BufReader::new(file.take(4096));
+    for _ in 0..2 {
+        if reader.read_until(b'\n', &mut head).is_err() {
+            return false;
+        }
+    }
+    format::has_header(&String::from_utf8_lossy(&head), header)
+}
+
+/// Remove generated file `path` inside `root`, along with directories it leaves empty.
+pub fn remove_within(root: &Path, path: &Path) -> Result<()> {
+    check_within(root, path)?;
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).wrap_err_with(|| format!("Failed to remove {}", path.display()));
+        }
+        _ => {}
+    }
+    for dir in path.ancestors().skip(1).take_while(|dir| *dir != root) {
+        // Stop at the first directory which isn't empty.
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+impl Outcome {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Updated => "updated",
+            Self::Unchanged => "unchanged",
+        }
+    }
+}
+
+/// Write `content` to `path` atomically, unless it's already there.
+pub fn write_if_changed(path: &Path, content: &str) -> Result<Outcome> {
+    let outcome = match fs::read(path) {
+        Ok(existing) if existing == content.as_bytes() => return Ok(Outcome::Unchanged),
+        Ok(_) => Outcome::Updated,
+        Err(_) => Outcome::Created,
+    };
+    write_atomic(path, content.as_bytes())?;
+    Ok(outcome)
+}
+
+/// Counts of written files by outcome.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+}
+
+impl Summary {
+    pub fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Created => self.created += 1,
+            Outcome::Updated => self.updated += 1,
+            Outcome::Unchanged => self.unchanged += 1,
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} created, {} updated, {} unchanged",
+            self.created, self.updated, self.unchanged
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::output::{check_within, is_generated, remove_within, write_if_changed, Outcome};
+
+    #[test]
+    fn must_write_only_changed_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("a.nix");
+
+        assert_eq!(write_if_changed(&path, "a").unwrap(), Outcome::Created);
+        // New files get the same mode as those created by `File::create`.
+        let created = temp_dir.path().join("created");
+        fs::File::create(&created).unwrap();
+        let mode = |path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), mode(&created));
+        fs::remove_file(&created).unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+
+        assert_eq!(write_if_changed(&path, "a").unwrap(), Outcome::Unchanged);
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+
+        assert_eq!(write_if_changed(&path, "b").unwrap(), Outcome::Updated);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "b");
+        assert_eq!(mode(&path), 0o600);
+        // No temporary file is left behind.
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn must_not_escape_output_root() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(temp_dir.path()).unwrap().join("out");
+        fs::create_dir(&root).unwrap();
+        std::os::unix::fs::symlink(temp_dir.path(), root.join("link")).unwrap();
+
+        assert!(check_within(&root, &root.join("a/b.nix")).is_ok());
+        assert!(check_within(&root, &root.join("../b.nix")).is_err());
+        assert!(check_within(&root, &root.join("link/b.nix")).is_err());
+        assert!(check_within(&root, &temp_dir.path().join("b.nix")).is_err());
+
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/c.nix"), "").unwrap();
+        fs::write(root.join("a/d.nix"), "").unwrap();
+        remove_within(&root, &root.join("a/b/c.nix")).unwrap();
+        assert!(!root.join("a/b").exists());
+        assert!(root.join("a/d.nix").exists());
+    }
+
+    #[test]
+    fn must_detect_generated_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("a.sh");
+        let header = "# Managed by nix-template.";
+
+        fs::write(&path, "#!/bin/sh\n# Managed by nix-template.\necho\n").unwrap();
+        assert!(is_generated(&path, header));
+        fs::write(&path, "echo\n# Managed by nix-template.\n").unwrap();
+        assert!(!is_generated(&path, header));
+        // Mentioning the header isn't enough.
+        fs::write(
+            &path,
+            "# Unlike files Managed by nix-template., this one is not\n",
+        )
+        .unwrap();
+        assert!(!is_generated(&path, header));
+        assert!(!is_generated(&temp_dir.path().join("b.sh"), header));
+    }
+}
@@ -1,29 +1,151 @@
-use std::sync::Mutex;
-use std::time::Duration;
+//! Progress reporting.
+//!
+//! Each in-flight helper call gets its own spinner row, and finished calls collapse into a summary
+//! line. When stderr is not a terminal, plain timestamped log lines are printed instead.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use indicatif::ProgressBar;
+use console::Emoji;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
-static GLOBAL_PROGRESS_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+const EMOJI_DONE: Emoji = Emoji("✔️ ", "");
 
-pub fn new_global_progress_bar(f: impl FnOnce() -> ProgressBar) -> ProgressBar {
-    let mut guard = GLOBAL_PROGRESS_BAR.lock().unwrap();
-    if let Some(old_pb) = guard.take() {
-        old_pb.finish();
+static GLOBAL_REPORTER: Mutex<Option<Reporter>> = Mutex::new(None);
+
+#[derive(Clone)]
+struct Reporter {
+    /// `None` if stderr is not a terminal.
+    multi: Option<MultiProgress>,
+    summary: ProgressBar,
+    completed: Arc<AtomicUsize>,
+}
+
+impl Reporter {
+    fn new() -> Self {
+        let multi = console::user_attended_stderr().then(MultiProgress::new);
+        let summary =
+            ProgressBar::new_spinner().with_style(ProgressStyle::with_template("{msg}").unwrap());
+        Self {
+            multi,
+            summary,
+            completed: Arc::default(),
+        }
+    }
+}
+
+fn log_line(msg: &str) {
+    eprintln!(
+        "[{}] {}",
+        humantime::format_rfc3339_seconds(SystemTime::now()),
+        msg
+    );
+}
+
+fn global_reporter() -> Option<Reporter> {
+    GLOBAL_REPORTER.lock().unwrap().clone()
+}
+
+pub fn new_global_reporter() {
+    delete_global_reporter();
+    *GLOBAL_REPORTER.lock().unwrap() = Some(Reporter::new());
+}
+
+pub fn delete_global_reporter() {
+    if let Some(reporter) = GLOBAL_REPORTER.lock().unwrap().take() {
+        reporter.summary.finish();
+    }
+}
+
+/// Print a line above all progress rows.
+pub fn println(msg: impl AsRef<str>) {
+    match global_reporter() {
+        Some(Reporter {
+            multi: Some(multi), ..
+        }) => {
+            // Failing to draw progress is not worth aborting for.
+            let _ = multi.println(msg);
+        }
+        Some(_) => log_line(msg.as_ref()),
+        None => {}
     }
-    let pb = f();
-    pb.enable_steady_tick(Duration::from_millis(60));
-    *guard = Some(pb.clone());
-    pb
 }
 
-pub fn with_global_progress_bar(f: impl FnOnce(ProgressBar)) {
-    if let Some(pb) = GLOBAL_PROGRESS_BAR.lock().unwrap().clone() {
-        f(pb);
+/// Hide all progress rows while running `f`, e.g. to print to stdout.
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    match global_reporter() {
+        Some(Reporter {
+            multi: Some(multi), ..
+        }) => multi.suspend(f),
+        _ => f(),
     }
 }
 
-pub fn delete_global_progress_bar() {
-    if let Some(old_pb) = GLOBAL_PROGRESS_BAR.lock().unwrap().take() {
-        old_pb.finish();
+/// Report an in-flight task until the returned guard is dropped.
+pub fn task(msg: impl Into<String>) -> Task {
+    let msg = msg.into();
+    let reporter = global_reporter();
+    let bar = match &reporter {
+        Some(Reporter {
+            multi: Some(multi), ..
+        }) => {
+            let bar = multi.add(
+                ProgressBar::new_spinner()
+                    .with_style(
+                        ProgressStyle::with_template("{spinner} [{elapsed}] {msg}").unwrap(),
+                    )
+                    .with_message(msg.clone()),
+            );
+            bar.enable_steady_tick(Duration::from_millis(100));
+            Some(bar)
+        }
+        Some(_) => {
+            log_line(&msg);
+            None
+        }
+        None => None,
+    };
+    Task {
+        reporter,
+        bar,
+        msg,
+        started: Instant::now(),
+    }
+}
+
+pub struct Task {
+    reporter: Option<Reporter>,
+    bar: Option<ProgressBar>,
+    msg: String,
+    started: Instant,
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        let Some(reporter) = &self.reporter else {
+            return;
+        };
+        let completed = reporter.completed.fetch_add(1, Ordering::SeqCst) + 1;
+        match (&reporter.multi, &self.bar) {
+            (Some(multi), Some(bar)) => {
+                bar.finish_and_clear();
+                multi.remove(bar);
+                if completed == 1 {
+                    multi.insert(0, reporter.summary.clone());
+                }
+                reporter
+                    .summary
+                    .set_message(format!("{EMOJI_DONE}{completed} task(s) completed"));
+            }
+            _ => {
+                // Sub-millisecond precision is just noise.
+                let elapsed = Duration::from_millis(self.started.elapsed().as_millis() as u64);
+                log_line(&format!(
+                    "{} (done in {})",
+                    self.msg,
+                    humantime::format_duration(elapsed)
+                ));
+            }
+        }
     }
 }
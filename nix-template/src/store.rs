@@ -1,10 +1,21 @@
-use std::fs::File;
-use std::io::{Seek, SeekFrom};
-use std::sync::{Arc, Mutex};
-
-use eyre::eyre;
-use log::info;
-
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+use eyre::{eyre, WrapErr};
+use fs2::FileExt;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::events::{self, Cache, Event};
+use crate::output::write_atomic;
 use crate::{Result, LOCK_FORMAT};
 
 static GLOBAL_STORE: Mutex<Option<Arc<dyn Store + Send + Sync>>> = Mutex::new(None);
@@ -21,66 +32,381 @@ pub fn get_global_store() -> Arc<dyn Store + Send + Sync> {
     GLOBAL_STORE.lock().unwrap().clone().unwrap()
 }
 
+thread_local! {
+    static CURRENT_TEMPLATE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Attribute cache accesses on the current thread to `template`, until the guard is dropped.
+pub fn enter_template(template: &str) -> TemplateGuard {
+    CURRENT_TEMPLATE.with(|current| *current.borrow_mut() = Some(template.to_string()));
+    TemplateGuard(())
+}
+
+fn current_template() -> Option<String> {
+    CURRENT_TEMPLATE.with(|current| current.borrow().clone())
+}
+
+pub struct TemplateGuard(());
+
+impl Drop for TemplateGuard {
+    fn drop(&mut self) {
+        CURRENT_TEMPLATE.with(|current| *current.borrow_mut() = None);
+    }
+}
+
 pub trait Store {
-    fn try_get_cached(&self, path: &[String]) -> Option<String>;
-    fn put_cache(&self, path: &[String], value: String);
+    fn try_get_cached(&self, path: &[String]) -> Option<serde_json::Value>;
+    fn put_cache(&self, path: &[String], value: serde_json::Value);
+    /// Whether a cache miss should be an error instead of computing the value.
+    fn frozen(&self) -> bool {
+        false
+    }
+}
+
+/// Per-path locks held while computing a cached value.
+type KeyedLocks = Mutex<HashMap<Vec<String>, Arc<Mutex<()>>>>;
+
+static IN_FLIGHT: Lazy<KeyedLocks> = Lazy::new(Mutex::default);
+
+/// Forgets the lock of a path in `IN_FLIGHT` once the call holding it is done.
+struct InFlight<'a> {
+    path: &'a [String],
+    lock: Arc<Mutex<()>>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut locks = IN_FLIGHT.lock().unwrap_or_else(PoisonError::into_inner);
+        // A later call might have installed a new lock after ours was forgotten.
+        if locks
+            .get(self.path)
+            .is_some_and(|lock| Arc::ptr_eq(lock, &self.lock))
+        {
+            locks.remove(self.path);
+        }
+    }
+}
+
+/// Resolve the value of `path` from the global store, or compute it with `f` and cache it.
+///
+/// This is the runtime part of helpers marked with `#[helper_func(cached)]`.
+pub fn cached<T: Serialize + DeserializeOwned>(
+    path: &[String],
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T, minijinja::Error> {
+    let to_error = |e: &dyn std::fmt::Display| {
+        minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, e.to_string())
+    };
+
+    let store = get_global_store();
+    let lookup = || {
+        let cache = store.try_get_cached(path)?;
+        match serde_json::from_value(cache) {
+            Ok(value) => Some(value),
+            // The lock file might be written by an older version with a different value type.
+            Err(e) => {
+                warn!("Ignoring malformed cache entry {:?}: {}", path, e);
+                None
+            }
+        }
+    };
+    let key = path.join("/");
+    let template = current_template();
+    let emit_call = |cache| {
+        events::emit(&Event::HelperCall {
+            key: &key,
+            cache,
+            template: template.as_deref(),
+        });
+    };
+    if let Some(value) = lookup() {
+        emit_call(Cache::Hit);
+        return Ok(value);
+    }
+    if store.frozen() {
+        return Err(to_error(&format!(
+            "Missing lock entry `{key}` while lock file is frozen"
+        )));
+    }
+
+    // Identical calls from other templates wait for the one in flight, and reuse its value.
+    let in_flight = IN_FLIGHT
+        .lock()
+        .unwrap()
+        .entry(path.to_vec())
+        .or_default()
+        .clone();
+    let _in_flight = in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+    // Dropped before the lock above, so that waiters find the value in the cache.
+    let _forget = InFlight {
+        path,
+        lock: in_flight.clone(),
+    };
+    if let Some(value) = lookup() {
+        emit_call(Cache::Hit);
+        return Ok(value);
+    }
+    emit_call(Cache::Miss);
+    let started = Instant::now();
+    let value = f().map_err(|e| to_error(&e))?;
+    events::emit(&Event::Fetch {
+        key: &key,
+        duration_ms: started.elapsed().as_millis(),
+    });
+    store.put_cache(
+        path,
+        serde_json::to_value(&value).map_err(|e| to_error(&e))?,
+    );
+    Ok(value)
 }
 
+type Predicate = Arc<dyn Fn(&[String]) -> bool + Send + Sync>;
+
 #[derive(Clone)]
 pub struct FileStore {
-    file: Arc<File>,
+    path: PathBuf,
+    /// The opened lock file, unless it doesn't exist and won't be written.
+    file: Arc<Option<File>>,
+    /// Content of the lock file when it was opened.
+    original: Arc<serde_json::Value>,
     data: Arc<Mutex<serde_json::Value>>,
+    frozen: bool,
+    /// Entries matching this predicate are refetched once, even if they are present.
+    refresh: Option<Predicate>,
+    refreshed: Arc<Mutex<HashSet<Vec<String>>>>,
+    /// Accessed entries, and the templates accessing them.
+    accessed: Arc<Mutex<BTreeMap<Vec<String>, BTreeSet<String>>>>,
 }
 
 impl FileStore {
-    /// Create a new store from a file.
+    fn new(
+        path: &Path,
+        file: Option<File>,
+        original: serde_json::Value,
+        data: serde_json::Value,
+    ) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            file: Arc::new(file),
+            original: Arc::new(original),
+            data: Arc::new(Mutex::new(data)),
+            frozen: false,
+            refresh: None,
+            refreshed: Arc::default(),
+            accessed: Arc::default(),
+        }
+    }
+    /// Create a new store from a file opened at `path`, or an empty one if there's no file.
     ///
     /// `load` specifies whether to load the file into memory.
-    pub fn with(file: File, load: bool) -> Result<Self> {
+    pub fn with(path: &Path, file: Option<File>, load: bool) -> Result<Self> {
         fn check_version(data: &serde_json::Value) -> bool {
-            data.get("version")
-                .and_then(serde_json::Value::as_u64)
-                .map_or(false, |v| v == LOCK_FORMAT as u64)
+            data.get("version").and_then(serde_json::Value::as_u64) == Some(LOCK_FORMAT as u64)
         }
         fn is_empty(e: &serde_json::Error) -> bool {
             e.is_eof() && e.line() == 1 && e.column() == 0
         }
-        if load {
-            info!("Loading cache...");
-            match serde_json::from_reader(&file) {
-                Ok(data) if check_version(&data) => Ok(Self {
-                    file: Arc::new(file),
-                    data: Arc::new(Mutex::new(data)),
-                }),
-                Err(e) if !is_empty(&e) => Err(e.into()),
-                // This is the case when the file is empty, or the format version doesn't match.
-                // We just create an empty object.
-                _ => Ok(Self {
-                    file: Arc::new(file),
-                    data: Arc::new(Mutex::new(serde_json::json!({ "version": LOCK_FORMAT }))),
-                }),
-            }
-        } else {
-            // We don't load the file, so we just create an empty object.
-            Ok(Self {
-                file: Arc::new(file),
-                data: Arc::new(Mutex::new(serde_json::json!({ "version": LOCK_FORMAT }))),
-            })
+        let empty = || serde_json::json!({ "version": LOCK_FORMAT });
+        info!("Loading cache...");
+        let Some(opened) = &file else {
+            return Ok(Self::new(path, file, empty(), empty()));
+        };
+        let original = match serde_json::from_reader(opened) {
+            Ok(data) if check_version(&data) => data,
+            // The file is going to be overwritten anyway if we don't load it.
+            Err(e) if load && !is_empty(&e) => return Err(e.into()),
+            // This is the case when the file is empty, or the format version doesn't match.
+            // We just create an empty object.
+            _ => empty(),
+        };
+        // The original content is still kept to report changes.
+        let data = if load { original.clone() } else { empty() };
+        Ok(Self::new(path, file, original, data))
+    }
+    /// Forbid computing values missing from the lock file.
+    #[must_use]
+    pub const fn freeze(mut self, frozen: bool) -> Self {
+        self.frozen = frozen;
+        self
+    }
+    /// Refetch entries whose path matches `predicate`, while keeping all other entries.
+    ///
+    /// Each matching entry is refetched at most once.
+    #[must_use]
+    pub fn refresh(
+        mut self,
+        predicate: impl Fn(&[String]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.refresh = Some(Arc::new(predicate));
+        self
+    }
+    fn should_refresh(&self, path: &[String]) -> bool {
+        self.refresh
+            .as_ref()
+            .is_some_and(|predicate| predicate(path))
+    }
+    /// Returns a copy of the lock file content.
+    pub fn snapshot(&self) -> serde_json::Value {
+        self.data.lock().unwrap().clone()
+    }
+    /// Returns all entries accessed so far, and the templates accessing them.
+    pub fn accessed(&self) -> BTreeMap<Vec<String>, BTreeSet<String>> {
+        self.accessed.lock().unwrap().clone()
+    }
+    /// Remove entries which haven't been accessed so far, and return their paths.
+    ///
+    /// Unused subtrees are removed as a whole, so a returned path may be a prefix of entries.
+    pub fn prune(&self) -> Vec<Vec<String>> {
+        fn prune_object(
+            object: &mut serde_json::Map<String, serde_json::Value>,
+            path: &mut Vec<String>,
+            accessed: &BTreeMap<Vec<String>, BTreeSet<String>>,
+            removed: &mut Vec<Vec<String>>,
+        ) {
+            object.retain(|key, value| {
+                path.push(key.clone());
+                // Keys are sorted, so the first key not less than `path` is a descendant if any is.
+                let used = accessed.contains_key(&*path)
+                    || accessed
+                        .range(path.clone()..)
+                        .next()
+                        .is_some_and(|(key, _)| key.starts_with(path));
+                if used {
+                    if !accessed.contains_key(&*path) {
+                        if let Some(object) = value.as_object_mut() {
+                            prune_object(object, path, accessed, removed);
+                        }
+                    }
+                } else {
+                    removed.push(path.clone());
+                }
+                path.pop();
+                used
+            });
+        }
+
+        let accessed = self.accessed.lock().unwrap();
+        let mut data = self.data.lock().unwrap();
+        let mut removed = vec![];
+        let version = data.as_object_mut().unwrap().remove("version");
+        prune_object(
+            data.as_object_mut().unwrap(),
+            &mut vec![],
+            &accessed,
+            &mut removed,
+        );
+        if let Some(version) = version {
+            data.as_object_mut()
+                .unwrap()
+                .insert("version".to_string(), version);
         }
+        removed
     }
+    /// Write the store back to its file.
+    ///
+    /// The file is replaced atomically, so that an interrupted write never leaves a truncated lock
+    /// file behind.
     pub fn persist(self) -> Result<()> {
-        let mut file = Arc::try_unwrap(self.file)
+        // The advisory lock is held until the original file is replaced.
+        let file = Arc::try_unwrap(self.file)
             .map_err(|_| eyre!("FileStore instance is not unique (multiple references)"))?;
-        file.seek(SeekFrom::Start(0))?;
-        file.set_len(0)?;
-        serde_json::to_writer_pretty(file, &*self.data.lock().unwrap())?;
+        let data = self.data.lock().unwrap();
+        if events::enabled() {
+            emit_removed(&self.original, &data, &mut vec![]);
+        }
+        let content = serde_json::to_vec_pretty(&*data)?;
+        write_atomic(&self.path, &content)
+            .wrap_err_with(|| format!("Failed to write lock file {}", self.path.display()))?;
+        drop(file);
         Ok(())
     }
 }
 
+/// Report entries of `original` missing from `data` as removed.
+fn emit_removed(original: &serde_json::Value, data: &serde_json::Value, path: &mut Vec<String>) {
+    for (key, old) in original.as_object().into_iter().flatten() {
+        path.push(key.clone());
+        match data.get(key) {
+            Some(new) if new.is_object() => emit_removed(old, new, path),
+            Some(_) => {}
+            None => events::emit(&Event::LockEntryChanged {
+                key: &path.join("/"),
+                old: Some(old),
+                new: None,
+            }),
+        }
+        path.pop();
+    }
+}
+
+fn lookup<'a>(data: &'a serde_json::Value, path: &[String]) -> Option<&'a serde_json::Value> {
+    path.iter().try_fold(data, |data, key| data.get(key))
+}
+
+/// Open the lock file at `path`, and take an advisory lock on it until the file is closed.
+///
+/// Writable lock files are locked exclusively and created if missing. Read-only ones are locked
+/// shared, so that concurrent readers don't block each other, and `None` is returned if missing.
+pub fn open_lock_file(path: &Path, writable: bool) -> Result<Option<File>> {
+    loop {
+        let file = if writable {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+        } else {
+            match OpenOptions::new().read(true).open(path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                file => file,
+            }
+        }
+        .wrap_err_with(|| format!("Failed to open lock file {}", path.display()))?;
+        // Inherent methods of the same names exist on newer toolchains, so we name the trait.
+        let locked = if writable {
+            FileExt::try_lock_exclusive(&file)
+        } else {
+            FileExt::try_lock_shared(&file)
+        };
+        if let Err(e) = locked {
+            if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+                return Err(eyre!(
+                    "Lock file {} is in use by another nix-template instance",
+                    path.display()
+                ));
+            }
+            return Err(e).wrap_err_with(|| format!("Failed to lock {}", path.display()));
+        }
+        // Persisting replaces the lock file, so the file we opened might have been replaced by the
+        // instance holding the lock before we got it. Its lock protects nothing then.
+        if is_current(&file, path) {
+            return Ok(Some(file));
+        }
+    }
+}
+
+/// Whether `file` is still the one at `path`.
+fn is_current(file: &File, path: &Path) -> bool {
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(opened), Ok(current)) => opened.dev() == current.dev() && opened.ino() == current.ino(),
+        _ => false,
+    }
+}
+
 impl Store for FileStore {
-    fn try_get_cached(&self, path: &[String]) -> Option<String> {
+    fn try_get_cached(&self, path: &[String]) -> Option<serde_json::Value> {
         info!("cache access: {:?}", path);
+        let templates = current_template();
+        self.accessed
+            .lock()
+            .unwrap()
+            .entry(path.to_vec())
+            .or_default()
+            .extend(templates);
+        if self.should_refresh(path) && !self.refreshed.lock().unwrap().contains(path) {
+            return None;
+        }
         let mut data = self.data.lock().unwrap();
         let item =
             path[..path.len() - 1]
@@ -91,13 +417,22 @@ impl Store for FileStore {
                         .as_object_mut()
                         .unwrap()
                 });
-        item.get(path.last().unwrap())
-            .and_then(serde_json::Value::as_str)
-            .map(ToString::to_string)
+        item.get(path.last().unwrap()).cloned()
     }
 
-    fn put_cache(&self, path: &[String], value: String) {
+    fn put_cache(&self, path: &[String], value: serde_json::Value) {
         info!("cache put: {:?} = {}", path, value);
+        if self.should_refresh(path) {
+            self.refreshed.lock().unwrap().insert(path.to_vec());
+        }
+        let old = lookup(&self.original, path);
+        if old != Some(&value) {
+            events::emit(&Event::LockEntryChanged {
+                key: &path.join("/"),
+                old,
+                new: Some(&value),
+            });
+        }
         let mut data = self.data.lock().unwrap();
         let item =
             path[..path.len() - 1]
@@ -108,57 +443,39 @@ impl Store for FileStore {
                         .as_object_mut()
                         .unwrap()
                 });
-        item.insert(
-            path.last().unwrap().clone(),
-            serde_json::Value::String(value),
-        );
+        item.insert(path.last().unwrap().clone(), value);
     }
+
+    fn frozen(&self) -> bool {
+        self.frozen
+    }
+}
+
+/// A store that never caches anything, so that every value is computed.
+pub struct NoopStore;
+
+impl Store for NoopStore {
+    fn try_get_cached(&self, _path: &[String]) -> Option<serde_json::Value> {
+        None
+    }
+
+    fn put_cache(&self, _path: &[String], _value: serde_json::Value) {}
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
+    use std::fs::File;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-    use log::info;
     use minijinja::Environment;
+    use serde_json::json;
 
     use nix_template_macros::helper_func;
 
-    use crate::store::{FileStore, Store};
+    use crate::store::{is_current, open_lock_file, FileStore, Store, IN_FLIGHT};
+    use crate::testing::{with_store, MemoryStore};
     use crate::Result;
 
-    static TEST_LOCK: Mutex<()> = Mutex::new(());
-
-    #[derive(Clone, Default)]
-    pub struct MemoryStore(Arc<Mutex<HashMap<Vec<String>, String>>>);
-
-    impl MemoryStore {
-        pub fn new(map: HashMap<Vec<String>, String>) -> Self {
-            Self(Arc::new(Mutex::new(map)))
-        }
-    }
-
-    impl Store for MemoryStore {
-        fn try_get_cached(&self, path: &[String]) -> Option<String> {
-            info!("cache access: {:?}", path);
-            self.0.lock().unwrap().get(path).cloned()
-        }
-
-        fn put_cache(&self, path: &[String], value: String) {
-            info!("cache put: {:?} = {}", path, value);
-            self.0.lock().unwrap().insert(path.to_vec(), value);
-        }
-    }
-
-    fn with_store(store: impl Store + Send + Sync + 'static, f: impl FnOnce()) {
-        let _lock = TEST_LOCK.lock().unwrap();
-        let store = Arc::new(store);
-        super::set_global_store(store);
-        f();
-        super::delete_global_store();
-    }
-
     #[helper_func(cached = f)]
     fn f_hole(_a: usize, _b: &str) -> Result<String> {
         unreachable!()
@@ -182,11 +499,49 @@ mod tests {
         Ok("g".to_string())
     }
 
+    static SLOW_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    #[allow(clippy::unnecessary_wraps)]
+    #[helper_func(cached)]
+    fn slow(a: usize) -> Result<usize> {
+        SLOW_CALLS.fetch_add(1, Ordering::SeqCst);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        Ok(a)
+    }
+
+    #[test]
+    fn must_deduplicate_in_flight_calls() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store =
+            FileStore::with(temp_file.path(), Some(temp_file.reopen().unwrap()), true).unwrap();
+
+        let mut env = Environment::new();
+        env.add_function("slow", slow);
+        with_store(store, || {
+            std::thread::scope(|scope| {
+                for _ in 0..4 {
+                    scope.spawn(|| {
+                        assert_eq!(
+                            env.render_str("{{ slow(42) }}", minijinja::context!())
+                                .unwrap(),
+                            "42"
+                        );
+                    });
+                }
+            });
+        });
+        assert_eq!(SLOW_CALLS.load(Ordering::SeqCst), 1);
+        assert!(!IN_FLIGHT
+            .lock()
+            .unwrap()
+            .contains_key(&["slow".to_string(), "42".to_string()][..]));
+    }
+
     #[test]
     fn must_resolve_from_cache() {
         let store = MemoryStore::new(maplit::hashmap! {
-            vec!["f".to_string(), "1".to_string(), "foo".to_string()] => "1foo".to_string(),
-            vec!["f".to_string(), "2".to_string(), "bar".to_string()] => "2bar".to_string(),
+            vec!["f".to_string(), "1".to_string(), "foo".to_string()] => json!("1foo"),
+            vec!["f".to_string(), "2".to_string(), "bar".to_string()] => json!("2bar"),
         });
 
         let mut env = Environment::new();
@@ -203,7 +558,8 @@ mod tests {
     #[test]
     fn must_cache_to_file() {
         let temp_file = tempfile::NamedTempFile::new().unwrap();
-        let store = FileStore::with(temp_file.reopen().unwrap(), true).unwrap();
+        let store =
+            FileStore::with(temp_file.path(), Some(temp_file.reopen().unwrap()), true).unwrap();
 
         let mut env = Environment::new();
         env.add_function("f", f);
@@ -222,7 +578,9 @@ mod tests {
 
         eprintln!("{}", std::fs::read_to_string(temp_file.path()).unwrap());
 
-        let store = FileStore::with(temp_file.reopen().unwrap(), true).unwrap();
+        // The original file has been replaced.
+        let file = open_lock_file(temp_file.path(), false).unwrap();
+        let store = FileStore::with(temp_file.path(), file, true).unwrap();
 
         let mut env = Environment::new();
         env.add_function("f", f_hole);
@@ -238,4 +596,135 @@ mod tests {
             );
         });
     }
+
+    #[test]
+    fn must_read_missing_lock_file_as_empty() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("template.lock");
+        let file = open_lock_file(&path, false).unwrap();
+        assert!(file.is_none());
+        assert!(!path.exists());
+        assert_eq!(
+            FileStore::with(&path, file, true).unwrap().snapshot(),
+            json!({ "version": crate::LOCK_FORMAT })
+        );
+    }
+
+    #[test]
+    fn must_reject_lock_file_in_use() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("template.lock");
+        let _file = open_lock_file(&path, true).unwrap();
+        let err = open_lock_file(&path, true).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("in use by another nix-template instance"));
+        let err = open_lock_file(&path, false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("in use by another nix-template instance"));
+    }
+
+    #[test]
+    fn must_lock_replaced_lock_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("template.lock");
+
+        // Two runs in sequence, each seeing what the previous one wrote.
+        for (i, key) in ["f", "g"].into_iter().enumerate() {
+            let file = open_lock_file(&path, true).unwrap();
+            // Opened by the next run before this one replaces the file.
+            let stale = File::open(&path).unwrap();
+            let store = FileStore::with(&path, file, true).unwrap();
+            assert_eq!(store.snapshot().as_object().unwrap().len(), i + 1);
+            store.put_cache(&[key.to_string()], json!(key));
+            store.persist().unwrap();
+
+            assert!(!is_current(&stale, &path));
+            assert!(is_current(
+                &open_lock_file(&path, false).unwrap().unwrap(),
+                &path
+            ));
+        }
+        let file = open_lock_file(&path, false).unwrap();
+        assert_eq!(
+            FileStore::with(&path, file, true).unwrap().snapshot(),
+            json!({ "version": crate::LOCK_FORMAT, "f": "f", "g": "g" })
+        );
+    }
+
+    #[test]
+    fn must_not_compute_when_frozen() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = FileStore::with(temp_file.path(), Some(temp_file.reopen().unwrap()), true)
+            .unwrap()
+            .freeze(true);
+        store.put_cache(&["g".to_string()], json!("g"));
+
+        let mut env = Environment::new();
+        env.add_function("f", f_hole);
+        env.add_function("g", g_hole);
+        with_store(store, || {
+            assert_eq!(
+                env.render_str("{{ g() }}", minijinja::context!()).unwrap(),
+                "g"
+            );
+            let err = env
+                .render_str("{{ f(1, 'foo') }}", minijinja::context!())
+                .unwrap_err();
+            assert!(err.to_string().contains("Missing lock entry `f/1/foo`"));
+        });
+    }
+
+    #[test]
+    fn must_prune_unaccessed_entries() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store =
+            FileStore::with(temp_file.path(), Some(temp_file.reopen().unwrap()), true).unwrap();
+        store.put_cache(&["f".into(), "1".into(), "foo".into()], json!("1foo"));
+        store.put_cache(&["f".into(), "1".into(), "bar".into()], json!("1bar"));
+        store.put_cache(&["f".into(), "2".into(), "bar".into()], json!("2bar"));
+        store.put_cache(&["g".into()], json!("g"));
+
+        let mut env = Environment::new();
+        env.add_function("f", f_hole);
+        with_store(store.clone(), || {
+            assert_eq!(
+                env.render_str("{{ f(1, 'foo') }}", minijinja::context!())
+                    .unwrap(),
+                "1foo"
+            );
+        });
+
+        let removed: Vec<_> = store.prune().iter().map(|path| path.join("/")).collect();
+        assert_eq!(removed, ["f/1/bar", "f/2", "g"]);
+        assert_eq!(
+            store.snapshot(),
+            json!({ "version": crate::LOCK_FORMAT, "f": { "1": { "foo": "1foo" } } })
+        );
+    }
+
+    #[test]
+    fn must_refresh_matching_entries() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store =
+            FileStore::with(temp_file.path(), Some(temp_file.reopen().unwrap()), true).unwrap();
+        store.put_cache(&["f".into(), "1".into(), "foo".into()], json!("stale"));
+        store.put_cache(&["f".into(), "2".into(), "bar".into()], json!("2bar"));
+        let store = store.refresh(|path| path[0] == "f" && path[1] == "1");
+
+        let mut env = Environment::new();
+        env.add_function("f", f);
+        env.add_function("f_hole", f_hole);
+        with_store(store, || {
+            assert_eq!(
+                env.render_str(
+                    "{{ f(1, 'foo') }} {{ f_hole(1, 'foo') }} {{ f_hole(2, 'bar') }}",
+                    minijinja::context!()
+                )
+                .unwrap(),
+                "1foo 1foo 2bar"
+            );
+        });
+    }
 }
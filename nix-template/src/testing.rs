@@ -0,0 +1,120 @@
+//! Test utilities.
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::info;
+
+use crate::store::{delete_global_store, set_global_store, Store};
+
+/// NAR hash (base64) of the tree created by `nested_dirs(_, true)`, generated by `nix-store --dump`.
+pub const NESTED_DIRS_NAR_HASH: &str = "4727rEh3BSKn2u49BndPwcpmbndb35TFGVlIkexj3/4=";
+
+static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Default)]
+pub struct MemoryStore(pub Arc<Mutex<HashMap<Vec<String>, serde_json::Value>>>);
+
+impl MemoryStore {
+    pub fn new(map: HashMap<Vec<String>, serde_json::Value>) -> Self {
+        Self(Arc::new(Mutex::new(map)))
+    }
+}
+
+impl Store for MemoryStore {
+    fn try_get_cached(&self, path: &[String]) -> Option<serde_json::Value> {
+        info!("cache access: {:?}", path);
+        self.0.lock().unwrap().get(path).cloned()
+    }
+
+    fn put_cache(&self, path: &[String], value: serde_json::Value) {
+        info!("cache put: {:?} = {}", path, value);
+        self.0.lock().unwrap().insert(path.to_vec(), value);
+    }
+}
+
+/// Run `f` with `store` installed as the global store.
+pub fn with_store(store: impl Store + Send + Sync + 'static, f: impl FnOnce()) {
+    let _lock = TEST_LOCK
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    set_global_store(Arc::new(store));
+    f();
+    delete_global_store();
+}
+
+/// A minimal HTTP server serving static responses, as a stand-in for remote hosts.
+pub struct TestServer {
+    pub url: String,
+    /// Raw request heads received so far.
+    pub requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl TestServer {
+    /// Start a server which serves `routes` (path -> body) and responds 404 to anything else.
+    pub fn serve(routes: HashMap<String, Vec<u8>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let requests_ = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut head = String::new();
+                let mut reader = BufReader::new(&mut stream);
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+                requests_.lock().unwrap().push(head);
+                let (status, body) = routes
+                    .get(&path)
+                    .map_or(("404 Not Found", &[][..]), |body| ("200 OK", body));
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        Self { url, requests }
+    }
+}
+
+pub fn write_file(path: &Path, content: &str, executable: bool) {
+    fs::write(path, content).unwrap();
+    let mode = if executable { 0o755 } else { 0o644 };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+}
+
+/// Populate `root` with a fixture tree of nested directories, files and an optional symlink.
+pub fn nested_dirs(root: &Path, with_symlink: bool) {
+    let some_dir = root.join("02-some-dir");
+    fs::create_dir_all(some_dir.join("more-depth")).unwrap();
+    write_file(&root.join("01-an-empty-file"), "", false);
+    write_file(&root.join("03-executable-file.exe"), "", true);
+    write_file(
+        &some_dir.join("small-file"),
+        "This is a test file.\n",
+        false,
+    );
+    write_file(&some_dir.join("more-depth/deep-empty-file"), "", false);
+    if with_symlink {
+        symlink(
+            "../01-an-empty-file",
+            some_dir.join("link-to-an-empty-file"),
+        )
+        .unwrap();
+    }
+}
@@ -1,21 +1,29 @@
+use std::io;
+use std::io::{Seek, SeekFrom};
+
 use eyre::eyre;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use xshell::{cmd, Shell};
 
 use nix_template_macros::helper_func;
 
-use crate::spinner::with_global_progress_bar;
+use crate::spinner;
 use crate::Result;
+use crate::{archive, fetch, hash, nar};
 use console::Emoji;
 
 const EMOJI_FETCH: Emoji = Emoji("📥 ", "");
 const EMOJI_HASH: Emoji = Emoji("🔑 ", "");
+const EMOJI_DOWNLOAD: Emoji = Emoji("🌐 ", "");
+const EMOJI_UNPACK: Emoji = Emoji("📦 ", "");
+const EMOJI_TAG: Emoji = Emoji("🏷️ ", "");
 
 /// Returns the commit hash of given git url and rev.
 #[helper_func(cached)]
-fn commit_of_git(url: &str, rev: &str) -> Result<String> {
-    with_global_progress_bar(|pb| {
-        pb.set_message(format!("{EMOJI_FETCH}Fetching commit of {url}#{rev}"))
-    });
+pub fn commit_of_git(url: &str, rev: &str) -> Result<String> {
+    let _task = spinner::task(format!("{EMOJI_FETCH}Fetching commit of {url}#{rev}"));
 
     let sh = Shell::new()?;
     let temp_dir = sh.create_temp_dir()?;
@@ -49,18 +57,8 @@ fn commit_of_github(owner: &str, repo: &str, rev: &str) -> Result<String> {
     )?)
 }
 
-/// Returns the sha256 hash of given git url and rev.
-#[helper_func(cached)]
-fn hash_from_git(url: &str, rev: &str) -> Result<String> {
-    with_global_progress_bar(|pb| {
-        pb.set_message(format!("{EMOJI_HASH}Calculating nix hash for {url}#{rev}"))
-    });
-
-    let sh = Shell::new()?;
-    let temp_dir = sh.create_temp_dir()?;
-    let temp_path = temp_dir.path();
-    sh.change_dir(temp_path);
-
+/// Shallow-fetch `rev` of `url` into the current directory of `sh` and check it out.
+fn checkout_git(sh: &Shell, url: &str, rev: &str) -> Result<()> {
     cmd!(sh, "git init")
         .ignore_stdout()
         .ignore_stderr()
@@ -78,14 +76,26 @@ fn hash_from_git(url: &str, rev: &str) -> Result<String> {
         .ignore_stderr()
         .quiet()
         .run()?;
+    Ok(())
+}
+
+/// Returns the sha256 hash of given git url and rev, in base64 like `nix hash path --base64`.
+#[helper_func(cached)]
+pub fn hash_from_git(url: &str, rev: &str) -> Result<String> {
+    let _task = spinner::task(format!("{EMOJI_HASH}Calculating nix hash for {url}#{rev}"));
+
+    let sh = Shell::new()?;
+    let temp_dir = sh.create_temp_dir()?;
+    let temp_path = temp_dir.path();
+    sh.change_dir(temp_path);
+
+    checkout_git(&sh, url, rev)?;
 
     sh.remove_path(".git")?;
-    Ok(cmd!(sh, "nix hash path --type sha256 --base64 {temp_path}")
-        .quiet()
-        .read()?)
+    Ok(base64::encode(nar::hash_path(temp_path)?))
 }
 
-/// Returns the sha256 hash of given repo and rev.
+/// Returns the sha256 hash of given repo and rev, in base64 like `nix hash path --base64`.
 #[helper_func]
 fn hash_from_github(owner: &str, repo: &str, rev: &str) -> Result<String> {
     Ok(hash_from_git(
@@ -93,3 +103,331 @@ fn hash_from_github(owner: &str, repo: &str, rev: &str) -> Result<String> {
         rev,
     )?)
 }
+
+/// A pinned git source.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GitSrc {
+    /// The commit hash.
+    rev: String,
+    /// The sha256 hash of the checkout, in SRI format.
+    hash: String,
+    /// The commit date, in `YYYY-MM-DD` format.
+    date: String,
+}
+
+/// Returns `{rev, hash, date}` of given git url and rev as a single lock entry.
+#[helper_func(cached)]
+fn git_src(url: &str, rev: &str) -> Result<GitSrc> {
+    let _task = spinner::task(format!("{EMOJI_FETCH}Fetching {url}#{rev}"));
+
+    let sh = Shell::new()?;
+    let temp_dir = sh.create_temp_dir()?;
+    let temp_path = temp_dir.path();
+    sh.change_dir(temp_path);
+
+    checkout_git(&sh, url, rev)?;
+    let commit = cmd!(sh, "git rev-parse HEAD").quiet().read()?;
+    let date = cmd!(sh, "git log -1 --format=%cs").quiet().read()?;
+
+    sh.remove_path(".git")?;
+    Ok(GitSrc {
+        rev: commit,
+        hash: hash::to_sri(&nar::hash_path(temp_path)?),
+        date,
+    })
+}
+
+/// Returns `{rev, hash, date}` of given repo and rev as a single lock entry.
+#[helper_func]
+fn github_src(owner: &str, repo: &str, rev: &str) -> Result<GitSrc> {
+    Ok(git_src(
+        &format!("https://github.com/{owner}/{repo}.git"),
+        rev,
+    )?)
+}
+
+/// Parse a version leniently, tolerating a `v` prefix and missing minor or patch components.
+fn parse_version(s: &str) -> Option<Version> {
+    let s = s.strip_prefix(['v', 'V']).unwrap_or(s);
+    Version::parse(s).ok().or_else(|| {
+        let (core, rest) = s
+            .find(['-', '+'])
+            .map_or((s, ""), |idx| (&s[..idx], &s[idx..]));
+        match core.split('.').count() {
+            1 => Version::parse(&format!("{core}.0.0{rest}")).ok(),
+            2 => Version::parse(&format!("{core}.0{rest}")).ok(),
+            _ => None,
+        }
+    })
+}
+
+/// Select the tag with the highest version satisfying `req`.
+///
+/// `pattern` describes how tags are named, with `{version}` as the placeholder of the version.
+fn select_latest_tag<'a>(
+    tags: impl IntoIterator<Item = &'a str>,
+    req: &VersionReq,
+    pattern: &str,
+) -> Option<&'a str> {
+    let (prefix, suffix) = pattern.split_once("{version}").unwrap_or((pattern, ""));
+    tags.into_iter()
+        .filter_map(|tag| {
+            let version = tag.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            Some((parse_version(version)?, tag))
+        })
+        .filter(|(version, _)| req.matches(version))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, tag)| tag)
+}
+
+/// Returns the highest tag of given git url satisfying a semver constraint, e.g. `^1.4`.
+#[helper_func]
+fn latest_tag_of_git(url: &str, constraint: &str) -> Result<String> {
+    Ok(latest_tag_of_git_matching(url, constraint, "{version}")?)
+}
+
+/// Returns the highest tag of given git url named after a pattern (e.g. `release-{version}`) satisfying a semver constraint.
+#[helper_func(cached)]
+fn latest_tag_of_git_matching(url: &str, constraint: &str, pattern: &str) -> Result<String> {
+    let _task = spinner::task(format!("{EMOJI_TAG}Fetching tags of {url}"));
+
+    let req = VersionReq::parse(constraint)?;
+    let sh = Shell::new()?;
+    let remotes = cmd!(sh, "git ls-remote --tags --refs {url}")
+        .quiet()
+        .read()?;
+    let tags = remotes
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter_map(|(_, name)| name.strip_prefix("refs/tags/"));
+    select_latest_tag(tags, &req, pattern)
+        .map(ToString::to_string)
+        .ok_or_else(|| eyre!("Could not find a tag matching {constraint} in {url}"))
+}
+
+/// Returns the sha256 hash (SRI format) of the file at given url, as expected by `fetchurl`.
+#[helper_func(cached)]
+fn hash_from_url(url: &str) -> Result<String> {
+    let _task = spinner::task(format!("{EMOJI_DOWNLOAD}Downloading {url}"));
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut fetch::get(url)?, &mut hasher)?;
+    Ok(hash::to_sri(&hasher.finalize()))
+}
+
+/// Returns the sha256 hash (nix base32 format) of the file at given url.
+#[helper_func]
+fn hash_from_url_base32(url: &str) -> Result<String> {
+    let sri = hash_from_url(url)?;
+    let digest = hash::from_sri(&sri).ok_or_else(|| eyre!("Invalid SRI hash: {sri}"))?;
+    Ok(hash::to_nix_base32(&digest))
+}
+
+/// Returns the sha256 hash (SRI format) of the unpacked archive at given url, as expected by `fetchzip`.
+#[helper_func(cached)]
+pub fn hash_from_tarball(url: &str) -> Result<String> {
+    let _task = spinner::task(format!("{EMOJI_UNPACK}Unpacking {url}"));
+
+    let mut archive = tempfile::tempfile()?;
+    io::copy(&mut fetch::get(url)?, &mut archive)?;
+    archive.seek(SeekFrom::Start(0))?;
+
+    let temp_dir = tempfile::tempdir()?;
+    let root = archive::unpack_stripped(&mut archive, temp_dir.path())?;
+    Ok(hash::to_sri(&nar::hash_path(&root)?))
+}
+
+/// Returns the sha256 hash (SRI format) of given repo and rev, as expected by `fetchFromGitHub`.
+#[helper_func]
+fn hash_from_github_tarball(owner: &str, repo: &str, rev: &str) -> Result<String> {
+    Ok(hash_from_tarball(&format!(
+        "https://github.com/{owner}/{repo}/archive/{rev}.tar.gz"
+    ))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use flate2::write::GzEncoder;
+    use xz2::write::XzEncoder;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use minijinja::Environment;
+    use xshell::{cmd, Shell};
+
+    use crate::testing::{nested_dirs, with_store, MemoryStore, TestServer, NESTED_DIRS_NAR_HASH};
+    use semver::VersionReq;
+
+    use crate::utils::{
+        hash_from_tarball, hash_from_url, hash_from_url_base32, parse_version, select_latest_tag,
+    };
+
+    fn tar_of(dir: &Path) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        builder.follow_symlinks(false);
+        builder.append_dir_all("input-master", dir).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn zip_of(dir: &Path) -> Vec<u8> {
+        fn add(zip: &mut ZipWriter<std::io::Cursor<Vec<u8>>>, root: &Path, path: &Path) {
+            let name = Path::new("input-master").join(path.strip_prefix(root).unwrap());
+            let name = name.to_string_lossy();
+            let metadata = fs::symlink_metadata(path).unwrap();
+            if metadata.is_symlink() {
+                let target = fs::read_link(path).unwrap();
+                zip.add_symlink(name, target.to_string_lossy(), FileOptions::default())
+                    .unwrap();
+            } else if metadata.is_dir() {
+                zip.add_directory(name, FileOptions::default()).unwrap();
+                for entry in fs::read_dir(path).unwrap() {
+                    add(zip, root, &entry.unwrap().path());
+                }
+            } else {
+                let options =
+                    FileOptions::default().unix_permissions(metadata.permissions().mode());
+                zip.start_file(name, options).unwrap();
+                zip.write_all(&fs::read(path).unwrap()).unwrap();
+            }
+        }
+        let mut zip = ZipWriter::new(std::io::Cursor::new(vec![]));
+        add(&mut zip, dir, dir);
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn must_hash_url() {
+        let server = TestServer::serve(maplit::hashmap! {
+            "/hello.txt".to_string() => b"hello world\n".to_vec(),
+        });
+        let url = format!("{}/hello.txt", server.url);
+        let store = MemoryStore::default();
+        with_store(store.clone(), || {
+            assert_eq!(
+                hash_from_url(&url).unwrap(),
+                "sha256-qUiQTy8PR5uPgZdpSzAYSw0u0cHNKh7A+4XSmaGSpEc="
+            );
+            assert_eq!(
+                hash_from_url_base32(&url).unwrap(),
+                "0ix4jahrkll5zg01wandq78jw3ab30q4nscph67rniqg5x7r0j59"
+            );
+            assert!(hash_from_url(&format!("{}/missing", server.url)).is_err());
+        });
+        // The second call must be served from the lock file.
+        assert_eq!(server.requests.lock().unwrap().len(), 2);
+        assert_eq!(
+            store
+                .0
+                .lock()
+                .unwrap()
+                .get(&vec!["hash_from_url".to_string(), url]),
+            Some(&serde_json::json!(
+                "sha256-qUiQTy8PR5uPgZdpSzAYSw0u0cHNKh7A+4XSmaGSpEc="
+            ))
+        );
+    }
+
+    #[test]
+    fn must_hash_tarball() {
+        let dir = tempfile::tempdir().unwrap();
+        nested_dirs(dir.path(), true);
+        let tar = tar_of(dir.path());
+
+        let mut tar_gz = GzEncoder::new(vec![], flate2::Compression::default());
+        tar_gz.write_all(&tar).unwrap();
+        let mut tar_xz = XzEncoder::new(vec![], 6);
+        tar_xz.write_all(&tar).unwrap();
+        let server = TestServer::serve(maplit::hashmap! {
+            "/input.tar.gz".to_string() => tar_gz.finish().unwrap(),
+            "/input.tar.xz".to_string() => tar_xz.finish().unwrap(),
+            "/input.zip".to_string() => zip_of(dir.path()),
+            "/input.tar".to_string() => tar,
+        });
+
+        let expected = format!("sha256-{NESTED_DIRS_NAR_HASH}");
+        with_store(MemoryStore::default(), || {
+            for ext in ["tar.gz", "tar.xz", "zip"] {
+                let url = format!("{}/input.{ext}", server.url);
+                assert_eq!(hash_from_tarball(&url).unwrap(), expected, "{ext}");
+            }
+            assert!(hash_from_tarball(&format!("{}/input.tar", server.url)).is_err());
+        });
+    }
+
+    #[test]
+    fn must_parse_version() {
+        assert_eq!(parse_version("v1.4.2").unwrap().to_string(), "1.4.2");
+        assert_eq!(parse_version("1.4").unwrap().to_string(), "1.4.0");
+        assert_eq!(parse_version("V2").unwrap().to_string(), "2.0.0");
+        assert_eq!(parse_version("1.5-rc.1").unwrap().to_string(), "1.5.0-rc.1");
+        assert!(parse_version("nightly").is_none());
+    }
+
+    #[test]
+    fn must_select_latest_tag() {
+        let tags = [
+            "v1.0.0",
+            "v1.4.2",
+            "v1.10.0-rc.1",
+            "v1.9",
+            "v2.0.0",
+            "nightly",
+            "release-1.11.0",
+        ];
+        let select = |req: &str, pattern: &str| {
+            select_latest_tag(tags, &VersionReq::parse(req).unwrap(), pattern)
+        };
+        assert_eq!(select("^1.4", "{version}"), Some("v1.9"));
+        assert_eq!(select("<3", "{version}"), Some("v2.0.0"));
+        assert_eq!(select("*", "{version}"), Some("v2.0.0"));
+        assert_eq!(select("~1.4", "v{version}"), Some("v1.4.2"));
+        assert_eq!(select("^1", "release-{version}"), Some("release-1.11.0"));
+        assert_eq!(select("^3", "{version}"), None);
+    }
+
+    #[test]
+    fn must_resolve_git_src() {
+        let repo = tempfile::tempdir().unwrap();
+        nested_dirs(repo.path(), true);
+        let sh = Shell::new().unwrap();
+        sh.change_dir(repo.path());
+        sh.set_var("GIT_COMMITTER_DATE", "2022-10-01T00:00:00Z");
+        cmd!(sh, "git init").quiet().ignore_stdout().run().unwrap();
+        cmd!(sh, "git add -A").quiet().run().unwrap();
+        cmd!(
+            sh,
+            "git -c user.name=test -c user.email=test@example.com commit -m init"
+        )
+        .quiet()
+        .ignore_stdout()
+        .run()
+        .unwrap();
+        let commit = cmd!(sh, "git rev-parse HEAD").quiet().read().unwrap();
+
+        let url = format!("file://{}", repo.path().display());
+        let mut env = Environment::new();
+        crate::populate_environment(&mut env);
+        let store = MemoryStore::default();
+        with_store(store.clone(), || {
+            assert_eq!(
+                env.render_str(
+                    "{% set src = git_src(url, 'HEAD') %}{{ src.rev }} {{ src.hash }} {{ src.date }}",
+                    minijinja::context!(url => url)
+                )
+                .unwrap(),
+                format!("{commit} sha256-{NESTED_DIRS_NAR_HASH} 2022-10-01")
+            );
+        });
+        let store = store.0.lock().unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(
+            store[&vec!["git_src".to_string(), url, "HEAD".to_string()]]["rev"],
+            commit
+        );
+    }
+}
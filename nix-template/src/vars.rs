@@ -0,0 +1,158 @@
+//! Variables in the render context of templates.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use eyre::{bail, eyre, WrapErr};
+
+use crate::Result;
+
+/// Extensions of supported variable files.
+const EXTENSIONS: &[&str] = &["toml", "json", "yaml", "yml"];
+
+/// Parse a `KEY=VALUE` pair.
+pub fn parse_var(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| eyre!("Invalid variable `{s}`, expected `KEY=VALUE`"))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Load variables from a TOML, JSON or YAML file, decided by its extension.
+pub fn load_file(path: &Path) -> Result<serde_json::Value> {
+    let content = fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read variables from {}", path.display()))?;
+    let vars: serde_json::Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&content).map_err(eyre::Report::from),
+        Some("json") => serde_json::from_str(&content).map_err(eyre::Report::from),
+        Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(eyre::Report::from),
+        _ => bail!(
+            "Unknown format of variables file {}, expected one of {}",
+            path.display(),
+            EXTENSIONS.join(", ")
+        ),
+    }
+    .wrap_err_with(|| format!("Failed to parse variables from {}", path.display()))?;
+    if !vars.is_object() {
+        bail!("Variables in {} must be a table", path.display());
+    }
+    Ok(vars)
+}
+
+/// Merge `other` into `base`. Tables are merged recursively, and other values are replaced.
+pub fn merge(base: &mut serde_json::Value, other: serde_json::Value) {
+    match (base, other) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
+
+/// Variables of all environment variables.
+pub fn env_vars() -> serde_json::Value {
+    std::env::vars()
+        .map(|(key, value)| (key, serde_json::Value::String(value)))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Variables shared by all templates.
+pub struct Context {
+    /// Variables from variables files and the environment.
+    base: serde_json::Value,
+    /// Variables from the command line, which take precedence over sidecar files.
+    overrides: serde_json::Value,
+}
+
+impl Context {
+    pub fn new(files: &[PathBuf], env: bool, vars: &[(String, String)]) -> Result<Self> {
+        let mut base = serde_json::json!({});
+        for path in files {
+            merge(&mut base, load_file(path)?);
+        }
+        if env {
+            merge(&mut base, serde_json::json!({ "env": env_vars() }));
+        }
+        let overrides = vars
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone().into()))
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+        Ok(Self { base, overrides })
+    }
+
+    /// Variables of `template`, including those in its sidecar file.
+    pub fn for_template(&self, template: Option<&Path>) -> Result<serde_json::Value> {
+        let mut vars = self.base.clone();
+        if let Some(sidecar) = template.and_then(sidecar_of) {
+            merge(&mut vars, load_file(&sidecar)?);
+        }
+        merge(&mut vars, self.overrides.clone());
+        Ok(vars)
+    }
+}
+
+fn sidecar_stem(name: &str) -> &str {
+    match name.rfind(".tmpl.") {
+        Some(idx) => &name[..idx + ".tmpl".len()],
+        None => name,
+    }
+}
+
+/// Whether file `name` holds variables of a template, e.g. `foo.tmpl.vars.toml`.
+pub fn is_sidecar(name: &str) -> bool {
+    EXTENSIONS.iter().any(|ext| {
+        name.strip_suffix(ext)
+            .and_then(|name| name.strip_suffix(".vars."))
+            .is_some_and(|stem| stem.ends_with(".tmpl"))
+    })
+}
+
+/// The file holding variables of `template`, e.g. `foo.tmpl.vars.toml` for `foo.tmpl.nix`.
+pub fn sidecar_of(template: &Path) -> Option<PathBuf> {
+    let name = template.file_name()?.to_str()?;
+    let stem = sidecar_stem(name);
+    EXTENSIONS
+        .iter()
+        .map(|ext| template.with_file_name(format!("{stem}.vars.{ext}")))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use crate::vars::{is_sidecar, load_file, merge, sidecar_of};
+
+    #[test]
+    fn must_load_and_merge_vars() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = dir.path().join("a.tmpl.vars.toml");
+        fs::write(&toml, "host = \"a\"\n[net]\nport = 1\nip = \"1.1.1.1\"\n").unwrap();
+        let yaml = dir.path().join("vars.yaml");
+        fs::write(&yaml, "net:\n  port: 2\nusers: [a, b]\n").unwrap();
+        fs::write(dir.path().join("a.tmpl.nix"), "").unwrap();
+
+        let mut vars = load_file(&toml).unwrap();
+        merge(&mut vars, load_file(&yaml).unwrap());
+        assert_eq!(
+            vars,
+            json!({ "host": "a", "net": { "port": 2, "ip": "1.1.1.1" }, "users": ["a", "b"] })
+        );
+
+        assert!(is_sidecar("a.tmpl.vars.toml"));
+        assert!(!is_sidecar("a.tmpl.nix"));
+        assert!(!is_sidecar("vars.toml"));
+        assert_eq!(sidecar_of(&dir.path().join("a.tmpl.nix")), Some(toml));
+        assert_eq!(sidecar_of(&dir.path().join("b.tmpl.nix")), None);
+    }
+}